@@ -1,55 +1,256 @@
-#![feature(phase)]
-
 ///////////////////////////////////////////////////////////////////////////////
 extern crate collections;
 extern crate getopts;
-extern crate regex;
-#[phase(plugin)] extern crate regex_macros;
+extern crate serialize;
 extern crate term;
 extern crate time;
 
 extern crate circ_comms;
 
+mod config;
+mod mirc;
+mod output;
+mod status;
+mod tui;
+
 ///////////////////////////////////////////////////////////////////////////////
 use circ_comms::Message;
+use output::Format;
 use collections::bitv::Bitv;
-use std::io::net::pipe::UnixStream;
+use std::cmp;
+use std::io;
 use std::os;
 use std::io::fs::PathExtensions;
 
 ///////////////////////////////////////////////////////////////////////////////
-fn process_args() -> (circ_comms::Request, bool, Vec<String>)
+enum Mode
+{
+    // a single request and whether a response is expected
+    Single(circ_comms::Request, bool),
+    // interactive mode, keeping track of read messages for the reader id
+    Tui(String)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// How to reach circd: the local socket unless an address is given
+#[deriving(Clone)]
+struct Server
+{
+    socket: Path,
+    address: Option<String>,
+    tls: Option<circ_comms::TlsSettings>,
+    secret: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Everything taken from the command line (and circ's configuration file)
+struct Options
+{
+    mode: Mode,
+    highlights: Vec<String>,
+    // drop mIRC formatting instead of showing it
+    strip: bool,
+    server: Server,
+    format: Format,
+    status: status::StatusSettings
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Dates are local days; the end of a range includes the whole day
+fn parse_date(date: &str, end_of_day: bool) -> Result<time::Timespec, String>
+{
+    let mut tm = match time::strptime(date, "%Y-%m-%d")
+        {
+            Ok(t)  => t,
+            Err(e) => return Err(format!("Invalid date {}: {}", date, e))
+        };
+
+    if end_of_day
+    {
+        tm.tm_hour = 23;
+        tm.tm_min = 59;
+        tm.tm_sec = 59;
+    }
+
+    tm.tm_utcoff = time::now().tm_utcoff;
+    Ok(tm.to_timespec())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// An option that has to be given for what was asked
+fn required<T>(value: Option<T>, message: &str) -> Result<T, String>
+{
+    match value
+    {
+        Some(v) => Ok(v),
+        None    => Err(message.to_string())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Channels can be given without the # (which needs escaping in the shell),
+// or by a unique prefix, only then is circd asked for its channels
+fn resolve_channel(server: &Server, network: &Option<String>, name: String,
+                   purpose: circ_comms::ChannelUse) -> Result<String, String>
+{
+    if name.starts_with("#") || name.starts_with("&")
+    {
+        return Ok(name);
+    }
+
+    let (mut stream, _) = try!(connect(server));
+    let id = circ_comms::next_id();
+    if let Err(e) = circ_comms::write_request(&mut stream, id, &circ_comms::Request::ListChannels)
+    {
+        return Err(format!("Unable to send request: {}", e));
+    }
+
+    let channels = match read_response(&mut stream, id)
+        {
+            Ok(circ_comms::Response::Channels(c)) => c,
+            Ok(r)  => return Err(format!("Unexpected response {}", r)),
+            Err(e) => return Err(e)
+        };
+
+    circ_comms::resolve_channel(name.as_slice(), network.as_ref().map(|n| n.as_slice()),
+                                &channels, purpose)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// How -s prints, from the configuration file and the --status options
+fn status_settings(matches: &getopts::Matches) -> Result<status::StatusSettings, String>
+{
+    let mut status = try!(config::load()).status;
+
+    if let Some(t) = matches.opt_str("status-format") { status.template = Some(t); }
+    if matches.opt_present("status-compact") { status.compact = true; }
+    if let Some(s) = matches.opt_str("status-separator") { status.separator = s; }
+    if matches.opt_present("status-quiet") { status.quiet = true; }
+    if let Some(e) = matches.opt_str("status-escape")
+    {
+        status.escape = match status::Escape::parse(e.as_slice())
+            {
+                Some(e) => e,
+                None    => return Err(format!("Unknown escape {}, expected none, zsh or bash", e))
+            };
+    }
+
+    Ok(status)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Errors come with the format they are to be reported in, as far as it is
+// known by then
+fn process_args() -> Result<Options, (Format, String)>
 {
    let opts : &[getopts::OptGroup] = 
         &[
             getopts::optflag("l", "list-channels", "List the channels that circd is using"),
             getopts::optopt("c", "channel", "Channel to use for the operations", "#rust"),
+            getopts::optopt("n", "network", "Network the channel belongs to", "mozilla"),
             getopts::optflag("j", "join", "Join a channel"),
             getopts::optflag("m", "msg", "Send a message to a channel"),
+            getopts::optflag("", "me", "Send an action (/me) to a channel"),
+            getopts::optopt("", "msg-user", "Send a private message to a nick", "nick"),
             getopts::optflag("p", "part", "Part from a channel"),
             getopts::optflag("q", "quit", "Quit irc and stop circd"),
+            getopts::optopt("", "nick", "Change your nick", "nick"),
             getopts::optflag("s", "status", "Get the unread message status of all channels"),
             getopts::optflag("u", "unread", "Get the unread messages from a channel"),
+            getopts::optflag("f", "follow", "Print messages on the channel (or all channels) as they arrive"),
+            getopts::optflag("", "peek", "Show the unread messages from a channel without marking them read"),
+            getopts::optopt("", "reader", "Keep track of read messages separately for this id (or set CIRC_READER)", "id"),
+            getopts::optflag("", "tui", "Interactive full screen mode"),
             getopts::optflag("w", "who", "Get the users currently active on the channel"),
-            getopts::optopt("h", "highlight", "List of words that would cause the line to be highlighted", "word1[,word2...]")
+            getopts::optopt("h", "highlight", "List of words that would cause the line to be highlighted", "word1[,word2...]"),
+            getopts::optflag("", "strip-formatting", "Show messages without their bold, colours and other formatting"),
+            getopts::optflag("", "raw", "Send the message as typed, without turning *bold* and _underline_ into formatting"),
+            getopts::optopt("", "search", "Search the history of a channel", "regex"),
+            getopts::optopt("", "since", "Only search history from this date on", "YYYY-MM-DD"),
+            getopts::optopt("", "until", "Only search history up to this date", "YYYY-MM-DD"),
+            getopts::optopt("", "user", "Only search history for messages from this nick", "nick"),
+            getopts::optopt("", "limit", "Maximum number of history results", "count"),
+            getopts::optopt("", "status-format", "Template for each channel in the status: {channel}, {network}, {unread}, {mentions} and colours like {red} and {reset}", "template"),
+            getopts::optflag("", "status-compact", "Print the status on one line"),
+            getopts::optopt("", "status-separator", "Separator between channels in the compact status (default a space)", "text"),
+            getopts::optflag("", "status-quiet", "Print nothing in the compact status when everything has been read"),
+            getopts::optopt("", "status-escape", "Mark colour codes for a zsh or bash prompt", "none|zsh|bash"),
+            getopts::optopt("", "format", "How to print responses: text (the default), plain, json, jsonl or tsv", "format"),
+            getopts::optopt("", "socket", "Socket of the local circd (or set CIRCD_SOCKET)", "path"),
+            getopts::optopt("", "connect", "Connect to a remote circd instead of the local one", "host:port"),
+            getopts::optopt("", "ca", "Use TLS, trusting circd's certificate if it is signed by this", "ca.pem"),
+            getopts::optopt("", "cert", "Use TLS, logging in with this client certificate", "cert.pem"),
+            getopts::optopt("", "key", "Private key of the client certificate", "key.pem"),
+            getopts::optopt("", "secret-file", "File holding the secret for a remote circd (or set CIRC_SECRET)", "file")
         ];
     
     let matches = match getopts::getopts(os::args().tail(), opts)
         {
             Ok(m) => m,
-            Err(e) => panic!("Invalid options\n{}", e)
+            Err(e) => return Err((Format::Text, format!("Invalid options\n{}", e)))
         };
 
+    let format = match matches.opt_str("format")
+        {
+            Some(f) => match Format::parse(f.as_slice())
+                {
+                    Some(f) => f,
+                    None    => return Err((Format::Text, format!("Unknown format {}, expected text, plain, json, jsonl or tsv", f)))
+                },
+            None => Format::Text
+        };
+
+    read_options(&matches, format.clone()).map_err(|e| (format, e))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn read_options(matches: &getopts::Matches, format: Format) -> Result<Options, String>
+{
     let channel = matches.opt_str("channel");
+    let network = matches.opt_str("network");
+
+    let reader = match matches.opt_str("reader").or(os::getenv("CIRC_READER"))
+        {
+            Some(r) => r,
+            None    => circ_comms::DEFAULT_READER.to_string()
+        };
+
+    let ca = matches.opt_str("ca").map(|c| Path::new(c));
+    let certificate = matches.opt_str("cert").map(|c| Path::new(c));
+    let key = matches.opt_str("key").map(|k| Path::new(k));
+
+    let tls = if ca.is_some() || certificate.is_some()
+              {
+                  Some(circ_comms::TlsSettings{certificate: certificate, key: key, ca: ca})
+              }
+              else
+              {
+                  None
+              };
+
+    let secret = match matches.opt_str("secret-file")
+        {
+            Some(f) => match io::File::open(&Path::new(f.as_slice())).read_to_string()
+                {
+                    Ok(s)  => Some(s.as_slice().trim().to_string()),
+                    Err(e) => return Err(format!("Unable to read {}: {}", f, e))
+                },
+            None => os::getenv("CIRC_SECRET")
+        };
 
-    let v = ["l", "j", "m", "p", "q", "s", "u", "w"];
+    let server = Server{socket: circ_comms::socket_path(matches.opt_str("socket"), None),
+                        address: matches.opt_str("connect"),
+                        tls: tls,
+                        secret: secret};
+
+    let v = ["l", "j", "m", "me", "p", "q", "s", "u", "f", "w", "peek", "search", "tui", "msg-user", "nick"];
     
     let flags : Vec<&str> = v.iter().filter(|&x| matches.opt_present(*x))
                              .map(|x| x.as_slice()).collect();
 
     if flags.len() > 1 || flags.len() == 0
     {
-        panic!("Must specify one of [l, j, m, p, q, s, u, w]");
+        return Err("Must specify one of [l, j, m, me, p, q, s, u, f, w, peek, search, tui, msg-user, nick]".to_string());
     }
 
     let highlights : Vec<String> = match matches.opt_str("highlight")
@@ -59,96 +260,231 @@ fn process_args() -> (circ_comms::Request, bool, Vec<String>)
         };
 
 
+    // what is sent, with *bold* and _underline_ turned into mIRC codes
+    // unless it is to go as typed
     let data = if matches.free.is_empty()
                {
                    None
                }
-               else
+               else if matches.opt_present("raw")
                {
                    Some(matches.free.connect(" "))
+               }
+               else
+               {
+                   Some(mirc::markup(matches.free.connect(" ").as_slice()))
                };
+
+    let purpose = match flags[0]
+        {
+            "j"        => circ_comms::ChannelUse::Joining,
+            "m" | "me" => circ_comms::ChannelUse::Sending,
+            _          => circ_comms::ChannelUse::Reading
+        };
+    let channel = match channel
+        {
+            Some(c) => Some(try!(resolve_channel(&server, &network, c, purpose))),
+            None    => None
+        };
+
+    // the configuration file is only for the status
+    let status = if flags[0] == "s" { try!(status_settings(matches)) } else { status::StatusSettings::new() };
+
+    let needs_channel = "A channel must be given with -c";
+    let needs_message = "The message must be given after the options";
        
-    match flags[0]
+    let (mode, highlights) = match flags[0]
     {
-        "l" => (circ_comms::Request::ListChannels, true, highlights),
-        "j" => (circ_comms::Request::Join(channel.unwrap()), false, highlights),
-        "m" => (circ_comms::Request::SendMessage(channel.unwrap(), data.unwrap()), false, highlights),
-        "p" => (circ_comms::Request::Part(channel.unwrap()), false, highlights),
-        "q" => (circ_comms::Request::Quit, false, highlights),
-        "s" => (circ_comms::Request::GetStatus, true, highlights),
-        "u" => (circ_comms::Request::GetMessages(channel.unwrap()), true, highlights),
-        x   => panic!("Unknown option {}", x)
-    }
+        "l" => (Mode::Single(circ_comms::Request::ListChannels, true), highlights),
+        "j" => (Mode::Single(circ_comms::Request::Join(network, try!(required(channel, needs_channel))), false), highlights),
+        "m" => (Mode::Single(circ_comms::Request::SendMessage(network, try!(required(channel, needs_channel)), try!(required(data, needs_message))), false), highlights),
+        "me" => (Mode::Single(circ_comms::Request::SendMessage(network, try!(required(channel, needs_channel)), format!("\x01ACTION {}\x01", try!(required(data, needs_message)))), false), highlights),
+        "msg-user" => (Mode::Single(circ_comms::Request::SendMessage(network, matches.opt_str("msg-user").unwrap(), try!(required(data, needs_message))), false), highlights),
+        "p" => (Mode::Single(circ_comms::Request::Part(network, try!(required(channel, needs_channel))), false), highlights),
+        "q" => (Mode::Single(circ_comms::Request::Quit, false), highlights),
+        "nick" => (Mode::Single(circ_comms::Request::ChangeNick(matches.opt_str("nick").unwrap()), false), highlights),
+        "s" => (Mode::Single(circ_comms::Request::GetStatus(reader), true), highlights),
+        "u" => (Mode::Single(circ_comms::Request::GetMessages(network, try!(required(channel, needs_channel)), reader), true), highlights),
+        "f" => (Mode::Single(circ_comms::Request::Subscribe(network, channel.into_iter().collect()), true), highlights),
+        // only scripts are given the away message and account of each user
+        "w" if format == Format::Json || format == Format::Jsonl =>
+            (Mode::Single(circ_comms::Request::GetUsersStatus(network, try!(required(channel, needs_channel))), true), highlights),
+        "w" => (Mode::Single(circ_comms::Request::GetUsers(network, try!(required(channel, needs_channel))), true), highlights),
+        "peek" => (Mode::Single(circ_comms::Request::Peek(network, try!(required(channel, needs_channel)), reader), true), highlights),
+        "tui" => (Mode::Tui(reader), highlights),
+        "search" =>
+        {
+            let since = match matches.opt_str("since")
+                {
+                    Some(d) => Some(try!(parse_date(d.as_slice(), false))),
+                    None    => None
+                };
+            let until = match matches.opt_str("until")
+                {
+                    Some(d) => Some(try!(parse_date(d.as_slice(), true))),
+                    None    => None
+                };
+            let limit = match matches.opt_str("limit")
+                {
+                    Some(l) => match from_str::<uint>(l.as_slice())
+                        {
+                            Some(n) => Some(n),
+                            None    => return Err(format!("Invalid limit {}", l))
+                        },
+                    None => None
+                };
+
+            let request = circ_comms::Request::SearchHistory
+                {
+                    network: network,
+                    channel: try!(required(channel, needs_channel)),
+                    pattern: matches.opt_str("search").unwrap(),
+                    since:   since,
+                    until:   until,
+                    user:    matches.opt_str("user"),
+                    limit:   limit
+                };
+            (Mode::Single(request, true), highlights)
+        },
+        x   => return Err(format!("Unknown option {}", x))
+    };
+
+    Ok(Options{mode: mode, highlights: highlights, strip: matches.opt_present("strip-formatting"),
+               server: server, format: format, status: status})
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn line_wrap(t: &mut Box<term::Terminal<term::WriterWrapper> + Send>, string: &str, indent: uint)
+// Where string is broken into lines that fit the terminal, as the start and
+// end of each
+fn wrap(string: &str, indent: uint) -> Vec<(uint, uint)>
 {
-    let width = match os::getenv("COLUMNS")
+    // COLUMNS that isn't a number is as good as not set
+    let width = match os::getenv("COLUMNS").and_then(|val| from_str::<uint>(val.as_slice()))
         {
-            Some(val) => from_str::<uint>(val.as_slice()).unwrap() - indent,
-            None      => std::uint::MAX
+            Some(columns) => cmp::max(columns.saturating_sub(indent), 1),
+            None          => std::uint::MAX
         };
 
     if width > string.len()
     {
-         (writeln!(t, "{}", string)).unwrap();
+        return vec![(0, string.len())];
     }
-    else
+
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    while start < string.len()
     {
-        let mut start = 0;
+        let mut end = start + width;
 
-        while start < string.len()
+        if end >= string.len()
         {
-            if start != 0
+            end = string.len()
+        }
+        else
+        {
+            // Find a decent end to the string
+            while string.char_at(end) != ' ' && end > start
             {
-                for _ in range(0, indent)
-                {
-                    (write!(t, " ")).unwrap();
-                }
+                end -= 1;
             }
-            let mut end = start + width;
 
-            if end >= string.len()
+            if end == start
+            {
+                // can't split in a nice spot.
+                end = start + width;
+            }
+        }
+
+        lines.push((start, end));
+
+        start = end + 1;
+    }
+
+    lines
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn line_wrap(t: &mut Box<term::Terminal<term::WriterWrapper> + Send>, string: &str, indent: uint)
+{
+    for (n, &(start, end)) in wrap(string, indent).iter().enumerate()
+    {
+        if n != 0
+        {
+            for _ in range(0, indent)
             {
-                end = string.len()
+                (write!(t, " ")).unwrap();
             }
-            else
+        }
+
+        (writeln!(t, "{}", string.slice(start, end))).unwrap();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// line_wrap for a message, showing its mIRC formatting on top of base
+fn formatted_wrap(t: &mut Box<term::Terminal<term::WriterWrapper> + Send>, text: &str,
+                  base: &mirc::Style, indent: uint)
+{
+    let spans = mirc::parse(text);
+    let plain = mirc::strip(text);
+
+    for (n, &(start, end)) in wrap(plain.as_slice(), indent).iter().enumerate()
+    {
+        if n != 0
+        {
+            t.reset().unwrap();
+            for _ in range(0, indent)
             {
-                // Find a decent end to the string
-                while string.char_at(end) != ' ' && end > start
-                {
-                    end -= 1;
-                }
-                
-                if end == start
-                {
-                    // can't split in a nice spot.
-                    end = start + width;
-                }
+                (write!(t, " ")).unwrap();
             }
+        }
 
-            (writeln!(t, "{}", string.slice(start, end))).unwrap();
+        let mut offset = 0;
+        for span in spans.iter()
+        {
+            let len = span.text.len();
+            let (from, to) = (cmp::max(offset, start), cmp::min(offset + len, end));
 
-            start = end + 1;
+            if from < to
+            {
+                span.style.over(base).apply(t);
+                (write!(t, "{}", span.text.slice(from - offset, to - offset))).unwrap();
+            }
+            offset += len;
         }
+
+        t.reset().unwrap();
+        (writeln!(t, "")).unwrap();
     }
 }
+
 ///////////////////////////////////////////////////////////////////////////////
 fn print_msgs(msgs: &Vec<Message>, highlights: &Vec<String>)
 {
     let mut t = term::stdout().unwrap();
-    
-    let re = regex!(r"\001ACTION (?P<action>[^\001]+)\001");
-    
+
+    write_msgs(&mut t, msgs, highlights, 0);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// margin is the number of columns to leave blank on the left of every line
+fn write_msgs(t: &mut Box<term::Terminal<term::WriterWrapper> + Send>,
+              msgs: &Vec<Message>, highlights: &Vec<String>, margin: uint)
+{
     for m in msgs.iter()
     {
         // TODO: There has to be a better way of doing this...
-        let vec : Vec<bool> = highlights.iter().map(|x| m.msg.as_slice().contains(x.as_slice())).collect();
+        let plain = mirc::strip(m.msg.as_slice());
+        let vec : Vec<bool> = highlights.iter().map(|x| plain.as_slice().contains(x.as_slice())).collect();
         let bvec: Bitv = vec.iter().map(|n| *n).collect();
         
         let highlight = bvec.any();
 
+        for _ in range(0, margin)
+        {
+            (write!(t, " ")).unwrap();
+        }
         (write!(t, "[")).unwrap();
         t.fg(term::color::MAGENTA).unwrap();
         (write!(t, "{}", time::at(m.time).strftime("%T").unwrap())).unwrap();
@@ -158,29 +494,32 @@ fn print_msgs(msgs: &Vec<Message>, highlights: &Vec<String>)
         let user = m.user.as_slice().split('!').next().unwrap();
         
 
-        match re.captures(m.msg.as_slice())
+        match (m.action(), &m.kind)
         {
-            Some(c) => 
+            (Some(action), _) =>
                 { 
                     t.fg(term::color::BLUE).unwrap();
                     (write!(t, "{: >12} ", user)).unwrap();
-                    if let Some(action) = c.name("action")
-                    {
-                        line_wrap(&mut t, action, 241);
-                    }
+                    let base = mirc::Style{fg: Some(term::color::BLUE), ..mirc::Style::new()};
+                    formatted_wrap(t, action, &base, margin + 24);
                     t.reset().unwrap();
                 },
-            None =>
+            (None, &Some(circ_comms::MessageKind::Ctcp(ref command))) =>
+                {
+                    t.fg(term::color::YELLOW).unwrap();
+                    (write!(t, "{: >12} ", user)).unwrap();
+                    line_wrap(t, format!("sent CTCP {} {}", command, m.msg).as_slice(), margin + 25);
+                    t.reset().unwrap();
+                },
+            _ =>
                 {
                     t.fg(term::color::GREEN).unwrap();
                     (write!(t, "{: >12}", user)).unwrap();
                     t.reset().unwrap();
                     (write!(t, "> ")).unwrap();
-                    if highlight
-                    {
-                        t.bg(term::color::BLUE).unwrap();
-                    }
-                    line_wrap(&mut t, m.msg.as_slice(), 25);
+                    let base = mirc::Style{bg: if highlight { Some(term::color::BLUE) } else { None },
+                                           ..mirc::Style::new()};
+                    formatted_wrap(t, m.msg.as_slice(), &base, margin + 25);
                     t.reset().unwrap();
                     //(writeln!(t, "")).unwrap();
                     
@@ -191,45 +530,302 @@ fn print_msgs(msgs: &Vec<Message>, highlights: &Vec<String>)
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn main()
+// How many lines write_msgs takes for a message once it has been wrapped
+fn message_lines(m: &Message, margin: uint) -> uint
 {
-    let (request, response_expected, highlights) = process_args();
-    
-    let socket = Path::new(circ_comms::address());
+    let (text, indent) = match (m.action(), &m.kind)
+        {
+            (Some(action), _) => (mirc::strip(action), 24),
+            (None, &Some(circ_comms::MessageKind::Ctcp(ref command))) =>
+                (format!("sent CTCP {} {}", command, m.msg), 25),
+            _ => (mirc::strip(m.msg.as_slice()), 25)
+        };
+
+    wrap(text.as_slice(), margin + indent).len()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What happened, for events other than messages
+fn describe_event(event: &circ_comms::Event) -> String
+{
+    match *event
+    {
+        circ_comms::Event::Message(ref c, _) => format!("message on {}", c),
+        circ_comms::Event::Join(ref c, ref nick, _) => format!("{} joined {}", nick, c),
+        circ_comms::Event::Part(ref c, ref nick, _) => format!("{} left {}", nick, c),
+        circ_comms::Event::Quit(ref c, ref nick, _) => format!("{} quit {}", nick, c),
+        circ_comms::Event::Topic(ref c, ref nick, ref topic, _) =>
+            format!("{} set the topic of {} to: {}", nick, c, topic),
+        circ_comms::Event::Nick(_, ref old, ref new, _) => format!("{} is now known as {}", old, new)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn print_event(event: circ_comms::Event, highlights: &Vec<String>)
+{
+    let mut t = term::stdout().unwrap();
+    let when = event.time();
+
+    let text = match event
+        {
+            circ_comms::Event::Message(_, m) => return print_msgs(&vec![m], highlights),
+            ref e => describe_event(e)
+        };
+
+    (write!(t, "[")).unwrap();
+    t.fg(term::color::MAGENTA).unwrap();
+    (write!(t, "{}", time::at(when).strftime("%T").unwrap())).unwrap();
+    t.reset().unwrap();
+    (write!(t, "] ")).unwrap();
+    t.fg(term::color::YELLOW).unwrap();
+    (write!(t, "{: >12} ", "--")).unwrap();
+    line_wrap(&mut t, text.as_slice(), 24);
+    t.reset().unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn read_response(stream: &mut circ_comms::Transport, id: uint) -> Result<circ_comms::Response, String>
+{
+    let (response_id, response) = match circ_comms::read_response(stream)
+        {
+            Ok(r)  => r,
+            Err(e) => return Err(format!("{}", e))
+        };
 
-    if socket.exists().not()
+    if response_id != id
     {
-        panic!("Socket {} doesn't exist", circ_comms::address());
+        return Err(format!("Response {} doesn't match request {}", response_id, id));
     }
 
-    let mut stream = UnixStream::connect(&socket).unwrap();
+    Ok(response)
+}
 
-    circ_comms::write_request(&mut stream, &request);
+///////////////////////////////////////////////////////////////////////////////
+// Connect to circd and say hello, returns the stream along with the
+// capabilities circd has
+fn connect(server: &Server) -> Result<(circ_comms::Transport, Vec<String>), String>
+{
+    let connected = match server.address
+        {
+            Some(ref a) => circ_comms::Transport::connect_tcp(a.as_slice(), server.tls.as_ref()),
+            None        => circ_comms::Transport::connect_unix(&server.socket)
+        };
+
+    let mut stream = match connected
+        {
+            Ok(s)  => s,
+            Err(e) => return Err(format!("Unable to connect to circd: {}", e))
+        };
+
+    match circ_comms::client_hello(&mut stream, server.secret.clone())
+    {
+        Ok(c)  => Ok((stream, c)),
+        Err(e) => Err(format!("{}", e))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Report an error and make circ exit with a failure status
+fn fail(message: String)
+{
+    let _ = writeln!(&mut io::stderr(), "circ: {}", message);
+    os::set_exit_status(1);
+}
 
-    if response_expected
+///////////////////////////////////////////////////////////////////////////////
+// --strip-formatting: the messages without their mIRC codes
+fn strip_messages(msgs: &mut Vec<Message>)
+{
+    for m in msgs.iter_mut()
     {
-        let response = circ_comms::read_response(&mut stream);
+        m.msg = mirc::strip(m.msg.as_slice());
+    }
+}
 
-        match response
+///////////////////////////////////////////////////////////////////////////////
+// Keep printing what circd pushes until it closes the connection
+fn follow(stream: &mut circ_comms::Transport, id: uint, highlights: &Vec<String>, strip: bool,
+          format: &Format)
+{
+    loop
+    {
+        match read_response(stream, id)
         {
-            circ_comms::Response::Channels(channels) => println!("{}", channels),
-            circ_comms::Response::Messages(m) => print_msgs(&m, &highlights),
-            circ_comms::Response::Status(s) => 
+            Ok(circ_comms::Response::Event(n, mut e)) =>
             {
-                for t in s.iter()
+                if let circ_comms::Event::Message(_, ref mut m) = e
                 {
-                    let (channel, count) = t.clone();
-                    if count == 1
-                    {
-                        println!("{} has 1 new message", channel);
-                    }
-                    else if count > 1
-                    {
-                        println!("{} has {} new messages", channel, count);
-                    }
+                    if strip { m.msg = mirc::strip(m.msg.as_slice()); }
+                }
+
+                if *format == Format::Text
+                {
+                    print_event(e, highlights)
+                }
+                else
+                {
+                    output::event(format, n.as_slice(), &e);
+                    io::stdio::flush();
                 }
             },
-            r => panic!("Unexpected response{}", r)
+            Ok(circ_comms::Response::Error(e)) => return output::error(format, format!("circd error: {}", e)),
+            Ok(r) => return output::error(format, format!("Unexpected response {}", r)),
+            Err(e) => return output::error(format, e)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn print_status(statuses: &Vec<circ_comms::NetworkStatus>)
+{
+    // Only name the network when there is more than one to tell apart
+    let show_network = statuses.len() > 1;
+
+    for status in statuses.iter()
+    {
+        let unread : Vec<&circ_comms::ChannelStatus> = status.channels.iter()
+                                                             .filter(|c| c.unread > 0)
+                                                             .collect();
+
+        if let circ_comms::ConnectionState::Reconnecting(attempts, retry_at) = status.connection
+        {
+            println!("{} is reconnecting ({} failed attempts, next at {})",
+                     status.network, attempts,
+                     time::at(retry_at).strftime("%T").unwrap());
+        }
+        else if show_network && !unread.is_empty()
+        {
+            println!("{}:", status.network);
+        }
+
+        if let circ_comms::AuthState::Failed(ref reason) = status.auth
+        {
+            println!("{} couldn't identify you: {}", status.network, reason);
+        }
+
+        for c in unread.iter()
+        {
+            let indent = if show_network { "  " } else { "" };
+
+            let messages = if c.unread == 1
+                           {
+                               "1 new message".to_string()
+                           }
+                           else
+                           {
+                               format!("{} new messages", c.unread)
+                           };
+
+            let mentions = match c.mentions
+                           {
+                               0 => String::new(),
+                               1 => " (1 mentions you)".to_string(),
+                               n => format!(" ({} mention you)", n)
+                           };
+
+            println!("{}{} has {}{}", indent, c.name, messages, mentions);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn main()
+{
+    let Options{mode, highlights, strip, server, format, status} = match process_args()
+        {
+            Ok(o)  => o,
+            Err((format, e)) => return output::error(&format, e)
+        };
+
+    let (request, response_expected) = match mode
+        {
+            Mode::Single(r, e) => (r, e),
+            Mode::Tui(reader)  => return tui::run(reader, highlights, strip, server)
+        };
+    
+    if server.address.is_none() && server.socket.exists().not()
+    {
+        return output::error(&format, format!("Socket {} doesn't exist, is circd running?",
+                                              server.socket.display()));
+    }
+
+    let (mut stream, capabilities) = match connect(&server)
+        {
+            Ok(c)  => c,
+            Err(e) => return output::error(&format, e)
+        };
+
+    // a circd that can't say more about the users still has their nicks
+    let request = match request
+        {
+            circ_comms::Request::GetUsersStatus(n, c) if !capabilities.iter().any(|x| x.as_slice() == "users-status") =>
+                circ_comms::Request::GetUsers(n, c),
+            r => r
+        };
+
+    if let Some(c) = request.capability()
+    {
+        if !capabilities.iter().any(|x| x.as_slice() == c)
+        {
+            return output::error(&format, format!("circd doesn't support {}, it is probably older than circ",
+                                                  c));
+        }
+    }
+
+    let id = circ_comms::next_id();
+    if let Err(e) = circ_comms::write_request(&mut stream, id, &request)
+    {
+        return output::error(&format, format!("Unable to send request: {}", e));
+    }
+
+    if let circ_comms::Request::Subscribe(_, _) = request
+    {
+        return follow(&mut stream, id, &highlights, strip, &format);
+    }
+
+    if !response_expected
+    {
+        // circd closes the connection without answering unless the
+        // request couldn't be passed on to a network
+        if let Ok((_, circ_comms::Response::Error(e))) = circ_comms::read_response(&mut stream)
+        {
+            output::error(&format, format!("circd error: {}", e));
         }
+        return;
+    }
+
+    let mut response = match read_response(&mut stream, id)
+        {
+            Ok(r)  => r,
+            Err(e) => return output::error(&format, e)
+        };
+
+    if let circ_comms::Response::Messages(_, ref mut m) = response
+    {
+        if strip { strip_messages(m); }
+    }
+
+    if format != Format::Text
+    {
+        return output::response(&format, &response);
+    }
+
+    match response
+    {
+        circ_comms::Response::Channels(networks) =>
+            for &(ref network, ref channels) in networks.iter()
+            {
+                println!("{}: {}", network, channels.connect(", "));
+            },
+        circ_comms::Response::Messages(_, m) => print_msgs(&m, &highlights),
+        circ_comms::Response::Status(s) =>
+            if status.is_custom() { status.print(&s) } else { print_status(&s) },
+        circ_comms::Response::Users(_, users) =>
+            for u in users.iter()
+            {
+                println!("{}", u);
+            },
+        circ_comms::Response::Error(e) => output::error(&format, format!("circd error: {}", e)),
+        r => output::error(&format, format!("Unexpected response {}", r))
     }
 }
@@ -0,0 +1,97 @@
+///////////////////////////////////////////////////////////////////////////////
+use serialize::json;
+
+use std::io::File;
+use std::io::fs::PathExtensions;
+use std::os;
+
+use status::{Escape, StatusSettings};
+
+///////////////////////////////////////////////////////////////////////////////
+// circ's own configuration, $XDG_CONFIG_HOME/circ/config.json (or
+// ~/.config/circ/config.json, or wherever CIRC_CONFIG says).  Options given on
+// the command line win over it.
+pub struct Config
+{
+    pub status: StatusSettings
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct StatusConfig
+{
+    format: Option<String>,
+    compact: Option<bool>,
+    separator: Option<String>,
+    quiet: Option<bool>,
+    escape: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct ConfigFile
+{
+    status: Option<StatusConfig>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn path() -> Option<Path>
+{
+    if let Some(p) = os::getenv("CIRC_CONFIG")
+    {
+        return Some(Path::new(p));
+    }
+
+    let directory = match (os::getenv("XDG_CONFIG_HOME"), os::homedir())
+        {
+            (Some(d), _)    => Path::new(d),
+            (None, Some(h)) => h.join(".config"),
+            (None, None)    => return None
+        };
+
+    Some(directory.join_many(&["circ", "config.json"]))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A missing file is the same as an empty one
+pub fn load() -> Result<Config, String>
+{
+    let mut config = Config{status: StatusSettings::new()};
+
+    let filename = match path()
+        {
+            Some(p) if p.exists() => p,
+            _ => return Ok(config)
+        };
+
+    let contents = match File::open(&filename).read_to_string()
+        {
+            Ok(s)  => s,
+            Err(e) => return Err(format!("Unable to read {}: {}", filename.display(), e))
+        };
+
+    let file: ConfigFile = match json::decode(contents.as_slice())
+        {
+            Ok(c)  => c,
+            Err(e) => return Err(format!("Invalid configuration in {}: {}", filename.display(), e))
+        };
+
+    if let Some(s) = file.status
+    {
+        if s.format.is_some() { config.status.template = s.format; }
+        if let Some(c) = s.compact { config.status.compact = c; }
+        if let Some(sep) = s.separator { config.status.separator = sep; }
+        if let Some(q) = s.quiet { config.status.quiet = q; }
+        if let Some(e) = s.escape
+        {
+            config.status.escape = match Escape::parse(e.as_slice())
+                {
+                    Some(e) => e,
+                    None    => return Err(format!("Unknown escape {} in {}, expected none, zsh or bash",
+                                                  e, filename.display()))
+                };
+        }
+    }
+
+    Ok(config)
+}
@@ -0,0 +1,319 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::iter::Peekable;
+use std::str::Chars;
+
+use term;
+use term::attr;
+use term::color;
+
+///////////////////////////////////////////////////////////////////////////////
+// The control codes of mIRC formatting, constants so they can be matched on
+const BOLD: char = '\x02';
+const COLOUR: char = '\x03';
+const HEX_COLOUR: char = '\x04';
+const RESET: char = '\x0f';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const STRIKETHROUGH: char = '\x1e';
+const UNDERLINE: char = '\x1f';
+
+///////////////////////////////////////////////////////////////////////////////
+// The 16 mIRC colours as near as the terminal's get
+static COLOURS: [color::Color, ..16] =
+    [color::BRIGHT_WHITE, color::BLACK, color::BLUE, color::GREEN,
+     color::BRIGHT_RED, color::RED, color::MAGENTA, color::YELLOW,
+     color::BRIGHT_YELLOW, color::BRIGHT_GREEN, color::CYAN, color::BRIGHT_CYAN,
+     color::BRIGHT_BLUE, color::BRIGHT_MAGENTA, color::BRIGHT_BLACK, color::WHITE];
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Clone, PartialEq)]
+pub struct Style
+{
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    pub fg: Option<color::Color>,
+    pub bg: Option<color::Color>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Style
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> Style
+    {
+        Style{bold: false, italic: false, underline: false, reverse: false, fg: None, bg: None}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // This style on top of another, such as the colour of an action
+    pub fn over(&self, base: &Style) -> Style
+    {
+        Style{bold: self.bold || base.bold,
+              italic: self.italic || base.italic,
+              underline: self.underline || base.underline,
+              reverse: self.reverse || base.reverse,
+              fg: self.fg.or(base.fg),
+              bg: self.bg.or(base.bg)}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Set the terminal to this style, from whatever it was
+    pub fn apply(&self, t: &mut Box<term::Terminal<term::WriterWrapper> + Send>)
+    {
+        t.reset().unwrap();
+
+        if self.bold      { t.attr(attr::Bold).unwrap(); }
+        if self.italic    { t.attr(attr::Italic(true)).unwrap(); }
+        if self.underline { t.attr(attr::Underline(true)).unwrap(); }
+        if self.reverse   { t.attr(attr::Reverse).unwrap(); }
+
+        if let Some(c) = self.fg { t.fg(c).unwrap(); }
+        if let Some(c) = self.bg { t.bg(c).unwrap(); }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Text in one style
+pub struct Span
+{
+    pub style: Style,
+    pub text: String
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Up to two digits of a colour number, 99 (or anything past the 16 colours)
+// being the default
+fn colour_number(chars: &mut Peekable<char, Chars>) -> Option<Option<color::Color>>
+{
+    let mut digits = String::new();
+
+    while digits.len() < 2 && chars.peek().map_or(false, |c| c.is_digit(10))
+    {
+        digits.push(chars.next().unwrap());
+    }
+
+    match from_str::<uint>(digits.as_slice())
+    {
+        Some(n) if n < COLOURS.len() => Some(Some(COLOURS[n])),
+        Some(_) => Some(None),
+        None    => None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Split text on its formatting codes, which are left out of the spans
+pub fn parse(text: &str) -> Vec<Span>
+{
+    let mut spans = Vec::new();
+    let mut style = Style::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next()
+    {
+        let previous = style.clone();
+
+        match c
+        {
+            BOLD      => style.bold = !style.bold,
+            ITALIC    => style.italic = !style.italic,
+            UNDERLINE => style.underline = !style.underline,
+            REVERSE   => style.reverse = !style.reverse,
+            RESET     => style = Style::new(),
+            MONOSPACE | STRIKETHROUGH => (),
+            // \x03fg[,bg], or \x03 alone to go back to the default colours
+            COLOUR =>
+                match colour_number(&mut chars)
+                {
+                    Some(fg) =>
+                    {
+                        style.fg = fg;
+
+                        // only a comma followed by a digit starts a background
+                        let mut ahead = chars.clone();
+                        if ahead.next() == Some(',') && ahead.peek().map_or(false, |c| c.is_digit(10))
+                        {
+                            chars.next();
+                            style.bg = colour_number(&mut chars).unwrap();
+                        }
+                    },
+                    None => { style.fg = None; style.bg = None; }
+                },
+            // \x04RRGGBB[,RRGGBB], which the terminal can't show
+            HEX_COLOUR =>
+            {
+                let mut ahead = chars.clone();
+                if ahead.by_ref().take(6).all(|c| c.is_digit(16))
+                {
+                    for _ in range(0u, 6) { chars.next(); }
+                }
+            },
+            c =>
+            {
+                current.push(c);
+                continue;
+            }
+        }
+
+        if style != previous && !current.is_empty()
+        {
+            spans.push(Span{style: previous, text: current});
+            current = String::new();
+        }
+    }
+
+    if !current.is_empty()
+    {
+        spans.push(Span{style: style, text: current});
+    }
+
+    spans
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The text without any formatting
+pub fn strip(text: &str) -> String
+{
+    let mut result = String::new();
+
+    for span in parse(text).iter()
+    {
+        result.push_str(span.text.as_slice());
+    }
+
+    result
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A marker next to another of its kind isn't at the edge of a word either
+fn is_boundary(c: Option<char>, marker: char) -> bool
+{
+    c.map_or(true, |c| !c.is_alphanumeric() && c != marker)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// *bold* and _underline_ as mIRC codes.  The markers have to be at the edges
+// of words, so snake_case, __init__ and 2*3*4 are left alone.
+pub fn markup(text: &str) -> String
+{
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len()
+    {
+        let c = chars[i];
+        let code = match c { '*' => BOLD, '_' => UNDERLINE, _ => { result.push(c); i += 1; continue } };
+
+        let before = if i == 0 { None } else { Some(chars[i - 1]) };
+        let opens = is_boundary(before, c) && chars.get(i + 1).map_or(false, |n| !n.is_whitespace() && *n != c);
+
+        let close = if opens
+                    {
+                        range(i + 2, chars.len()).find(|&j| chars[j] == c &&
+                                                            !chars[j - 1].is_whitespace() &&
+                                                            is_boundary(chars.get(j + 1).map(|n| *n), c))
+                    }
+                    else
+                    {
+                        None
+                    };
+
+        match close
+        {
+            Some(j) =>
+            {
+                let inner: String = chars.slice(i + 1, j).iter().map(|c| *c).collect();
+                result.push(code);
+                result.push_str(markup(inner.as_slice()).as_slice());
+                result.push(code);
+                i = j + 1;
+            },
+            None =>
+            {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    result
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{COLOURS, Style, markup, parse, strip};
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn colour_with_comma_but_no_background()
+    {
+        let spans = parse("\x0312,");
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].text.as_slice(), ",");
+        assert_eq!(spans[0].style.fg, Some(COLOURS[12]));
+        assert_eq!(spans[0].style.bg, None);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn colour_with_background()
+    {
+        let spans = parse("\x0304,01red");
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].style.fg, Some(COLOURS[4]));
+        assert_eq!(spans[0].style.bg, Some(COLOURS[1]));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn colour_code_alone_resets_colours()
+    {
+        let spans = parse("\x0304,01red\x03 plain");
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].text.as_slice(), "red");
+        assert_eq!(spans[1].text.as_slice(), " plain");
+        assert!(spans[1].style == Style::new());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn strip_removes_every_code()
+    {
+        assert_eq!(strip("\x02bold\x02 \x0304,01red\x03 \x1funder\x1f\x0f").as_slice(), "bold red under");
+        assert_eq!(strip("\x0312,").as_slice(), ",");
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn markup_bold_and_underline()
+    {
+        assert_eq!(markup("the *owner* frees it").as_slice(), "the \x02owner\x02 frees it");
+        assert_eq!(markup("_really_ now").as_slice(), "\x1freally\x1f now");
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn markup_nested()
+    {
+        assert_eq!(markup("*bold _both_ bold*").as_slice(), "\x02bold \x1fboth\x1f bold\x02");
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn markup_leaves_words_alone()
+    {
+        for text in ["snake_case_name", "__init__.py", "2*3*4", "a * b * c", "**"].iter()
+        {
+            assert_eq!(markup(*text).as_slice(), *text);
+        }
+    }
+}
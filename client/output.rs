@@ -0,0 +1,452 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::collections::BTreeMap;
+use std::mem;
+use std::os;
+
+use serialize::json;
+
+use time;
+
+use circ_comms;
+use circ_comms::{AuthState, ConnectionState, Event, Message, MessageKind, Response};
+
+///////////////////////////////////////////////////////////////////////////////
+// How responses are printed.  Text is the normal coloured output, the others
+// are meant for scripts and are described in the README; changing what they
+// print breaks someone's script.
+#[deriving(Clone, PartialEq)]
+pub enum Format
+{
+    Text,
+    Plain,
+    Json,
+    Jsonl,
+    Tsv
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Format
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn parse(name: &str) -> Option<Format>
+    {
+        match name
+        {
+            "text"  => Some(Format::Text),
+            "plain" => Some(Format::Plain),
+            "json"  => Some(Format::Json),
+            "jsonl" => Some(Format::Jsonl),
+            "tsv"   => Some(Format::Tsv),
+            _       => None
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct ChannelsOut
+{
+    kind: &'static str,
+    networks: Vec<NetworkChannelsOut>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct NetworkChannelsOut
+{
+    network: String,
+    channels: Vec<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct ChannelOut
+{
+    kind: &'static str,
+    network: String,
+    channel: String
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct StatusOut
+{
+    kind: &'static str,
+    networks: Vec<NetworkOut>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// retry_at is when the next reconnection attempt is due, in seconds since
+// the epoch.  auth is anonymous, pending, identified (as account) or failed
+// (because of auth_error).
+#[deriving(Encodable)]
+struct NetworkOut
+{
+    kind: &'static str,
+    network: String,
+    connected: bool,
+    failed_attempts: uint,
+    retry_at: Option<i64>,
+    auth: &'static str,
+    account: Option<String>,
+    auth_error: Option<String>,
+    channels: Vec<UnreadOut>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct UnreadOut
+{
+    kind: &'static str,
+    network: String,
+    channel: String,
+    unread: uint,
+    mentions: uint
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct MessagesOut
+{
+    kind: &'static str,
+    network: String,
+    messages: Vec<MessageOut>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// time is in seconds since the epoch, user is the full nick!user@host.  ctcp
+// is the command of a CTCP request or reply other than an action, with its
+// parameters as the text.  tags are the IRCv3 message tags when the server
+// sent any.
+#[deriving(Encodable)]
+struct MessageOut
+{
+    kind: &'static str,
+    network: String,
+    time: i64,
+    nick: String,
+    user: String,
+    text: String,
+    action: bool,
+    ctcp: Option<String>,
+    tags: Option<BTreeMap<String, String>>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// users is kept as the plain list of nicks it has always been, members adds
+// what is known about each of them (null from a circd that can't say)
+#[deriving(Encodable)]
+struct UsersOut
+{
+    kind: &'static str,
+    network: String,
+    users: Vec<String>,
+    members: Option<Vec<MemberOut>>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// away is the away message, account the services account, when known
+#[deriving(Encodable)]
+struct MemberOut
+{
+    name: String,
+    away: Option<String>,
+    account: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct UserOut
+{
+    kind: &'static str,
+    network: String,
+    user: String,
+    away: Option<String>,
+    account: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// event is one of message, join, part, quit, topic or nick.  text is the
+// message, the topic or the new nick.
+#[deriving(Encodable)]
+struct EventOut
+{
+    kind: &'static str,
+    event: &'static str,
+    network: String,
+    channel: String,
+    time: i64,
+    nick: String,
+    text: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Encodable)]
+struct ErrorOut
+{
+    kind: &'static str,
+    message: String
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn nick(user: &str) -> String
+{
+    user.split('!').next().unwrap().to_string()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Tabs and line breaks would break up a tsv line
+fn field(text: &str) -> String
+{
+    text.chars().map(|c| if c == '\t' || c == '\n' || c == '\r' { ' ' } else { c }).collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn message(network: &str, m: &Message) -> MessageOut
+{
+    let (text, is_action) = match m.action()
+        {
+            Some(a) => (a.to_string(), true),
+            None    => (m.msg.clone(), false)
+        };
+
+    let ctcp = match m.kind
+        {
+            Some(MessageKind::Ctcp(ref c)) => Some(c.clone()),
+            _ => None
+        };
+
+    MessageOut{kind: "message", network: network.to_string(), time: m.time.sec,
+               nick: nick(m.user.as_slice()), user: m.user.clone(), text: text, action: is_action,
+               ctcp: ctcp, tags: m.tags.clone()}
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn network(status: &circ_comms::NetworkStatus) -> NetworkOut
+{
+    let (connected, attempts, retry_at) = match status.connection
+        {
+            ConnectionState::Connected => (true, 0, None),
+            ConnectionState::Reconnecting(a, t) => (false, a, Some(t.sec))
+        };
+
+    let (auth, account, auth_error) = match status.auth
+        {
+            AuthState::Anonymous         => ("anonymous", None, None),
+            AuthState::Pending           => ("pending", None, None),
+            AuthState::Identified(ref a) => ("identified", Some(a.clone()), None),
+            AuthState::Failed(ref e)     => ("failed", None, Some(e.clone()))
+        };
+
+    let mut channels: Vec<UnreadOut> =
+        status.channels.iter()
+                       .map(|c| UnreadOut{kind: "unread", network: status.network.clone(),
+                                          channel: c.name.clone(), unread: c.unread,
+                                          mentions: c.mentions})
+                       .collect();
+    channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+
+    NetworkOut{kind: "network", network: status.network.clone(), connected: connected,
+               failed_attempts: attempts, retry_at: retry_at, auth: auth, account: account,
+               auth_error: auth_error, channels: channels}
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn plain_message(m: &Message)
+{
+    let time = time::at(m.time).strftime("%T").unwrap();
+
+    match (m.action(), &m.kind)
+    {
+        (Some(a), _) => println!("[{}] * {} {}", time, nick(m.user.as_slice()), a),
+        (None, &Some(MessageKind::Ctcp(ref c))) =>
+            println!("[{}] {} sent CTCP {} {}", time, nick(m.user.as_slice()), c, m.msg),
+        _ => println!("[{}] {}> {}", time, nick(m.user.as_slice()), m.msg)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Print a response in any format but Text
+pub fn response(format: &Format, response: &Response)
+{
+    match (format, response)
+    {
+        (&Format::Json, &Response::Channels(ref networks)) =>
+            println!("{}", json::encode(&ChannelsOut{
+                kind: "channels",
+                networks: networks.iter()
+                                  .map(|&(ref n, ref c)| NetworkChannelsOut{network: n.clone(),
+                                                                            channels: c.clone()})
+                                  .collect()})),
+        (&Format::Json, &Response::Status(ref statuses)) =>
+            println!("{}", json::encode(&StatusOut{kind: "status",
+                                                   networks: statuses.iter().map(|s| network(s)).collect()})),
+        (&Format::Json, &Response::Messages(ref n, ref msgs)) =>
+            println!("{}", json::encode(&MessagesOut{kind: "messages", network: n.clone(),
+                                                     messages: msgs.iter()
+                                                                   .map(|m| message(n.as_slice(), m))
+                                                                   .collect()})),
+        (&Format::Json, &Response::Users(ref n, ref users)) =>
+            println!("{}", json::encode(&UsersOut{kind: "users", network: n.clone(),
+                                                  users: users.clone(), members: None})),
+        (&Format::Json, &Response::UsersStatus(ref n, ref users)) =>
+            println!("{}", json::encode(&UsersOut{kind: "users", network: n.clone(),
+                                                  users: users.iter().map(|u| u.name.clone()).collect(),
+                                                  members: Some(users.iter()
+                                                                     .map(|u| MemberOut{name: u.name.clone(),
+                                                                                        away: u.away.clone(),
+                                                                                        account: u.account.clone()})
+                                                                     .collect())})),
+
+        (&Format::Jsonl, &Response::Channels(ref networks)) =>
+            for &(ref n, ref channels) in networks.iter()
+            {
+                for c in channels.iter()
+                {
+                    println!("{}", json::encode(&ChannelOut{kind: "channel", network: n.clone(),
+                                                            channel: c.clone()}));
+                }
+            },
+        (&Format::Jsonl, &Response::Status(ref statuses)) =>
+            for s in statuses.iter()
+            {
+                let mut out = network(s);
+                let channels = mem::replace(&mut out.channels, Vec::new());
+
+                println!("{}", json::encode(&out));
+                for c in channels.iter()
+                {
+                    println!("{}", json::encode(c));
+                }
+            },
+        (&Format::Jsonl, &Response::Messages(ref n, ref msgs)) =>
+            for m in msgs.iter()
+            {
+                println!("{}", json::encode(&message(n.as_slice(), m)));
+            },
+        (&Format::Jsonl, &Response::Users(ref n, ref users)) =>
+            for u in users.iter()
+            {
+                println!("{}", json::encode(&UserOut{kind: "user", network: n.clone(), user: u.clone(),
+                                                     away: None, account: None}));
+            },
+        (&Format::Jsonl, &Response::UsersStatus(ref n, ref users)) =>
+            for u in users.iter()
+            {
+                println!("{}", json::encode(&UserOut{kind: "user", network: n.clone(), user: u.name.clone(),
+                                                     away: u.away.clone(), account: u.account.clone()}));
+            },
+
+        (&Format::Tsv, &Response::Channels(ref networks)) =>
+            for &(ref n, ref channels) in networks.iter()
+            {
+                for c in channels.iter()
+                {
+                    println!("{}\t{}", n, c);
+                }
+            },
+        (&Format::Tsv, &Response::Status(ref statuses)) =>
+            for s in statuses.iter()
+            {
+                let out = network(s);
+                let state = if out.connected { "connected" } else { "reconnecting" };
+
+                for c in out.channels.iter()
+                {
+                    println!("{}\t{}\t{}\t{}\t{}", c.network, c.channel, c.unread, c.mentions, state);
+                }
+
+                // a network without unread messages still says how it is
+                if out.channels.is_empty()
+                {
+                    println!("{}\t\t0\t0\t{}", out.network, state);
+                }
+            },
+        (&Format::Tsv, &Response::Messages(_, ref msgs)) =>
+            for m in msgs.iter()
+            {
+                let out = message("", m);
+                println!("{}\t{}\t{}", out.time, out.nick, field(out.text.as_slice()));
+            },
+        (&Format::Tsv, &Response::Users(_, ref users)) =>
+            for u in users.iter()
+            {
+                println!("{}", u);
+            },
+
+        (&Format::Plain, &Response::Channels(ref networks)) =>
+            for &(ref n, ref channels) in networks.iter()
+            {
+                println!("{}: {}", n, channels.connect(", "));
+            },
+        (&Format::Plain, &Response::Status(ref statuses)) => super::print_status(statuses),
+        (&Format::Plain, &Response::Messages(_, ref msgs)) =>
+            for m in msgs.iter()
+            {
+                plain_message(m);
+            },
+        (&Format::Plain, &Response::Users(_, ref users)) =>
+            for u in users.iter()
+            {
+                println!("{}", u);
+            },
+
+        (_, &Response::Error(ref e)) => error(format, format!("circd error: {}", e)),
+        (_, r) => error(format, format!("Unexpected response {}", r))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Print an event from --follow in any format but Text
+pub fn event(format: &Format, network: &str, event: &Event)
+{
+    let (kind, channel, nick, text, time) = match *event
+        {
+            Event::Message(ref c, ref m) =>
+            {
+                let out = message(network, m);
+                ("message", c.clone(), out.nick, Some(out.text), out.time)
+            },
+            Event::Join(ref c, ref n, t) => ("join", c.clone(), n.clone(), None, t.sec),
+            Event::Part(ref c, ref n, t) => ("part", c.clone(), n.clone(), None, t.sec),
+            Event::Quit(ref c, ref n, t) => ("quit", c.clone(), n.clone(), None, t.sec),
+            Event::Topic(ref c, ref n, ref topic, t) =>
+                ("topic", c.clone(), n.clone(), Some(topic.clone()), t.sec),
+            Event::Nick(ref c, ref old, ref new, t) =>
+                ("nick", c.clone(), old.clone(), Some(new.clone()), t.sec)
+        };
+
+    match *format
+    {
+        Format::Json | Format::Jsonl =>
+            println!("{}", json::encode(&EventOut{kind: "event", event: kind, network: network.to_string(),
+                                                  channel: channel, time: time, nick: nick, text: text})),
+        Format::Tsv =>
+            println!("{}\t{}\t{}\t{}\t{}\t{}", kind, network, channel, time, nick,
+                     field(text.unwrap_or(String::new()).as_slice())),
+        _ =>
+            match *event
+            {
+                Event::Message(_, ref m) => plain_message(m),
+                ref e => println!("[{}] -- {}", time::at(time::Timespec::new(time, 0)).strftime("%T").unwrap(),
+                                  super::describe_event(e))
+            }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Errors are printed as an error object for json, so a script always gets
+// something it can parse, and to stderr otherwise.  Either way circ exits
+// with a failure status.
+pub fn error(format: &Format, message: String)
+{
+    match *format
+    {
+        Format::Json | Format::Jsonl =>
+        {
+            println!("{}", json::encode(&ErrorOut{kind: "error", message: message}));
+            os::set_exit_status(1);
+        },
+        _ => super::fail(message)
+    }
+}
@@ -0,0 +1,284 @@
+///////////////////////////////////////////////////////////////////////////////
+use circ_comms;
+
+///////////////////////////////////////////////////////////////////////////////
+// Colour codes have to be marked as taking no space or the shell gets the
+// length of the prompt wrong: %{ %} for zsh, \001 \002 (what \[ \] becomes)
+// for bash
+#[deriving(Clone, PartialEq)]
+pub enum Escape
+{
+    None,
+    Zsh,
+    Bash
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Escape
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn parse(name: &str) -> Option<Escape>
+    {
+        match name
+        {
+            "none" => Some(Escape::None),
+            "zsh"  => Some(Escape::Zsh),
+            "bash" => Some(Escape::Bash),
+            _      => None
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn code(&self, code: &str) -> String
+    {
+        match *self
+        {
+            Escape::None => code.to_string(),
+            Escape::Zsh  => format!("%{{{}%}}", code),
+            Escape::Bash => format!("\x01{}\x02", code)
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // zsh expands % sequences in the prompt
+    fn text(&self, text: &str) -> String
+    {
+        match *self
+        {
+            Escape::Zsh => text.replace("%", "%%"),
+            _           => text.to_string()
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+static COLOURS: &'static [(&'static str, &'static str)] =
+    &[("{reset}",   "\x1b[0m"),
+      ("{bold}",    "\x1b[1m"),
+      ("{red}",     "\x1b[31m"),
+      ("{green}",   "\x1b[32m"),
+      ("{yellow}",  "\x1b[33m"),
+      ("{blue}",    "\x1b[34m"),
+      ("{magenta}", "\x1b[35m"),
+      ("{cyan}",    "\x1b[36m")];
+
+///////////////////////////////////////////////////////////////////////////////
+// How circ -s prints the status when a template is given.  Each channel with
+// unread messages is printed with the template, on a line of its own or, when
+// compact, all on one line.
+#[deriving(Clone)]
+pub struct StatusSettings
+{
+    pub template: Option<String>,
+    pub compact: bool,
+    pub separator: String,
+    pub quiet: bool,
+    pub escape: Escape
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Used by compact mode without a template
+pub static COMPACT_TEMPLATE: &'static str = "{channel}:{unread}";
+
+// Printed in compact mode when everything has been read, unless quiet
+pub static ALL_READ: &'static str = "all read";
+
+///////////////////////////////////////////////////////////////////////////////
+impl StatusSettings
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> StatusSettings
+    {
+        StatusSettings{template: None, compact: false, separator: " ".to_string(),
+                       quiet: false, escape: Escape::None}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Whether the normal sentences are replaced
+    pub fn is_custom(&self) -> bool
+    {
+        self.template.is_some() || self.compact
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Everything is substituted in one pass, so a channel called {red} is
+    // printed as it is
+    fn expand(&self, template: &str, network: &str, channel: &circ_comms::ChannelStatus) -> String
+    {
+        let mut values: Vec<(&str, String)> =
+            COLOURS.iter().map(|&(name, code)| (name, self.escape.code(code))).collect();
+
+        values.push(("{network}", self.escape.text(network)));
+        values.push(("{channel}", self.escape.text(channel.name.as_slice())));
+        values.push(("{unread}", channel.unread.to_string()));
+        values.push(("{mentions}", channel.mentions.to_string()));
+
+        let values: Vec<(&str, &str)> =
+            values.iter().map(|&(name, ref value)| (name, value.as_slice())).collect();
+        circ_comms::substitute(template, values.as_slice())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The lines to print, none when there is nothing to say
+    fn lines(&self, statuses: &Vec<circ_comms::NetworkStatus>) -> Vec<String>
+    {
+        let template = match self.template
+            {
+                Some(ref t) => t.as_slice(),
+                None        => COMPACT_TEMPLATE
+            };
+
+        let mut entries = Vec::new();
+
+        for status in statuses.iter()
+        {
+            let mut channels: Vec<&circ_comms::ChannelStatus> =
+                status.channels.iter().filter(|c| c.unread > 0).collect();
+            channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+            for c in channels.iter()
+            {
+                entries.push(self.expand(template, status.network.as_slice(), *c));
+            }
+        }
+
+        if entries.is_empty()
+        {
+            if self.compact && !self.quiet
+            {
+                return vec![ALL_READ.to_string()];
+            }
+            return entries;
+        }
+
+        if self.compact
+        {
+            vec![entries.connect(self.separator.as_slice())]
+        }
+        else
+        {
+            entries
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn print(&self, statuses: &Vec<circ_comms::NetworkStatus>)
+    {
+        for line in self.lines(statuses).iter()
+        {
+            println!("{}", line);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{Escape, StatusSettings};
+
+    use circ_comms::{AuthState, ChannelStatus, ConnectionState, NetworkStatus};
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn statuses(channels: &[(&str, uint, uint)]) -> Vec<NetworkStatus>
+    {
+        let mut status = NetworkStatus::new("libera", ConnectionState::Connected,
+                                            AuthState::Anonymous);
+        status.channels = channels.iter()
+                                  .map(|&(name, unread, mentions)|
+                                       ChannelStatus{name: name.to_string(), unread: unread,
+                                                     mentions: mentions})
+                                  .collect();
+        vec![status]
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn settings(template: &str) -> StatusSettings
+    {
+        let mut settings = StatusSettings::new();
+        settings.template = Some(template.to_string());
+        settings
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn channel(name: &str) -> ChannelStatus
+    {
+        ChannelStatus{name: name.to_string(), unread: 5, mentions: 2}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn expands_each_placeholder()
+    {
+        let s = settings("");
+        let c = channel("#rust");
+
+        assert_eq!(s.expand("{network}", "libera", &c), "libera".to_string());
+        assert_eq!(s.expand("{channel}", "libera", &c), "#rust".to_string());
+        assert_eq!(s.expand("{unread}", "libera", &c), "5".to_string());
+        assert_eq!(s.expand("{mentions}", "libera", &c), "2".to_string());
+        assert_eq!(s.expand("{red}{bold}{reset}", "libera", &c), "\x1b[31m\x1b[1m\x1b[0m".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn leaves_unknown_placeholders_and_substituted_text_alone()
+    {
+        let s = settings("");
+
+        assert_eq!(s.expand("{colour} {channel}", "libera", &channel("#rust")),
+                   "{colour} #rust".to_string());
+        assert_eq!(s.expand("{channel}", "libera", &channel("#{unread}")),
+                   "#{unread}".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn escapes_for_the_shell()
+    {
+        let mut s = settings("");
+        let c = channel("#100%");
+
+        s.escape = Escape::Zsh;
+        assert_eq!(s.expand("{red}{channel}", "libera", &c), "%{\x1b[31m%}#100%%".to_string());
+
+        s.escape = Escape::Bash;
+        assert_eq!(s.expand("{red}{channel}", "libera", &c), "\x01\x1b[31m\x02#100%".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn prints_a_line_for_each_unread_channel()
+    {
+        let s = settings("{channel} {unread}");
+
+        assert_eq!(s.lines(&statuses(&[("#rust", 3, 0), ("#debian", 0, 0), ("#arch", 1, 1)])),
+                   vec!["#arch 1".to_string(), "#rust 3".to_string()]);
+        assert!(s.lines(&statuses(&[("#rust", 0, 0)])).is_empty());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn compact_puts_everything_on_one_line()
+    {
+        let mut s = StatusSettings::new();
+        s.compact = true;
+        s.separator = ", ".to_string();
+
+        assert_eq!(s.lines(&statuses(&[("#rust", 3, 0), ("#arch", 1, 1)])),
+                   vec!["#arch:1, #rust:3".to_string()]);
+        assert_eq!(s.lines(&statuses(&[("#rust", 0, 0)])), vec![super::ALL_READ.to_string()]);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn quiet_says_nothing_when_all_is_read()
+    {
+        let mut s = StatusSettings::new();
+        s.compact = true;
+        s.quiet = true;
+
+        assert!(s.lines(&statuses(&[("#rust", 0, 0)])).is_empty());
+        assert_eq!(s.lines(&statuses(&[("#rust", 2, 0)])), vec!["#rust:2".to_string()]);
+    }
+}
@@ -0,0 +1,563 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::cmp;
+use std::io;
+use std::io::Timer;
+use std::io::process::{Command, InheritFd};
+use std::os;
+use std::time::Duration;
+
+use term;
+use time;
+
+use circ_comms;
+use circ_comms::{Message, Request, Response, Transport};
+
+///////////////////////////////////////////////////////////////////////////////
+static SIDEBAR_WIDTH: uint = 20;
+
+// Messages kept in the message pane
+static PANE_LIMIT: uint = 500;
+
+///////////////////////////////////////////////////////////////////////////////
+// Send a request on a connection of its own.  Join, Part and SendMessage are
+// only answered when circd couldn't pass them on, otherwise the connection
+// is closed and None returned.
+fn request(server: &super::Server, request: Request) -> Option<Response>
+{
+    let mut stream = match super::connect(server)
+        {
+            Ok((s, _)) => s,
+            Err(_)     => return None
+        };
+
+    exchange(&mut stream, &request)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn exchange(stream: &mut Transport, request: &Request) -> Option<Response>
+{
+    let id = circ_comms::next_id();
+    if circ_comms::write_request(stream, id, request).is_err()
+    {
+        return None;
+    }
+
+    super::read_response(stream, id).ok()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A request without a response, returns false when circd refused it
+fn command(tui: &mut Tui, request: Request) -> bool
+{
+    match self::request(&tui.server, request)
+    {
+        Some(Response::Error(e)) => { tui.add(vec![notice(e.as_slice())]); false },
+        _ => true
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// (rows, columns) of the terminal
+fn terminal_size() -> (uint, uint)
+{
+    if let Ok(o) = Command::new("stty").arg("size").stdin(InheritFd(0)).output()
+    {
+        let size: Vec<uint> = String::from_utf8_lossy(o.output.as_slice())
+                                  .as_slice().words()
+                                  .filter_map(|w| from_str(w))
+                                  .collect();
+        if size.len() == 2
+        {
+            return (size[0], size[1]);
+        }
+    }
+
+    (24, 80)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Run stty on the terminal, returning what it printed
+fn stty(args: &[&str]) -> Option<String>
+{
+    match Command::new("stty").args(args).stdin(InheritFd(0)).output()
+    {
+        Ok(o)  => Some(String::from_utf8_lossy(o.output.as_slice()).into_string()),
+        Err(_) => None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn notice(text: &str) -> Message
+{
+    Message::new(time::get_time(), "--", text)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+struct Tui
+{
+    server: super::Server,
+    reader: String,
+    highlights: Vec<String>,
+    strip: bool,
+    statuses: Vec<circ_comms::NetworkStatus>,
+    network: Option<String>,
+    channel: Option<String>,
+    messages: Vec<Message>,
+    // what has been typed so far, kept here so a redraw doesn't lose it
+    typed: String,
+    // (rows, columns) of the terminal, checked every so often rather than
+    // on every key
+    size: (uint, uint),
+    // the connection refresh asks over, when circd keeps connections open
+    keep_alive: bool,
+    stream: Option<Transport>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Tui
+{
+    ///////////////////////////////////////////////////////////////////////////
+    fn new(server: super::Server, reader: String, highlights: Vec<String>, strip: bool,
+           keep_alive: bool) -> Tui
+    {
+        let mut tui = Tui{server: server, reader: reader, highlights: highlights, strip: strip,
+                          statuses: Vec::new(), network: None, channel: None, messages: Vec::new(),
+                          typed: String::new(), size: (0, 0), keep_alive: keep_alive, stream: None};
+        tui.resized();
+        tui
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Read the size of the terminal again, returns true when it has changed
+    fn resized(&mut self) -> bool
+    {
+        let size = terminal_size();
+        if size == self.size
+        {
+            return false;
+        }
+
+        self.size = size;
+        os::setenv("COLUMNS", size.1.to_string());
+        true
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A request that is answered, over the connection kept for them.  One
+    // that has gone stale (circd drops idle clients) is made again once.
+    fn ask(&mut self, request: Request) -> Option<Response>
+    {
+        if !self.keep_alive
+        {
+            return self::request(&self.server, request);
+        }
+
+        for _ in range(0u, 2)
+        {
+            if self.stream.is_none()
+            {
+                self.stream = super::connect(&self.server).ok().map(|(s, _)| s);
+            }
+
+            let response = match self.stream
+                {
+                    Some(ref mut s) => exchange(s, &request),
+                    None            => return None
+                };
+
+            if response.is_some()
+            {
+                return response;
+            }
+            self.stream = None;
+        }
+
+        None
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn add(&mut self, mut msgs: Vec<Message>)
+    {
+        if self.strip
+        {
+            super::strip_messages(&mut msgs);
+        }
+        self.messages.extend(msgs.into_iter());
+
+        if self.messages.len() > PANE_LIMIT
+        {
+            let start = self.messages.len() - PANE_LIMIT;
+            self.messages = self.messages.slice_from(start).to_vec();
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Pick up the unread counts and any new messages on the current channel
+    fn refresh(&mut self)
+    {
+        match self.ask(Request::GetStatus(self.reader.clone()))
+        {
+            Some(Response::Status(s)) => self.statuses = s,
+            _ => ()
+        }
+
+        let channel = match self.channel { Some(ref c) => c.clone(), None => return };
+
+        // circd doesn't know about a channel just joined until the server
+        // confirms it, so errors are ignored here
+        match self.ask(Request::GetMessages(self.network.clone(), channel, self.reader.clone()))
+        {
+            Some(Response::Messages(network, m)) =>
+            {
+                self.network = Some(network);
+                self.add(m);
+            },
+            _ => ()
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn switch(&mut self, channel: &str)
+    {
+        // stay on the same network unless the channel is only known elsewhere
+        let current = self.network.clone();
+        let networks: Vec<String> =
+            self.statuses.iter()
+                         .filter(|s| s.channels.iter().any(|c| c.name.as_slice() == channel))
+                         .map(|s| s.network.clone())
+                         .collect();
+
+        if networks.len() == 1 && current.as_ref().map_or(true, |n| !networks.contains(n))
+        {
+            self.network = Some(networks[0].clone());
+        }
+
+        self.channel = Some(channel.to_string());
+        self.messages.clear();
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn send(&mut self, target: &str, text: &str)
+    {
+        let request = Request::SendMessage(self.network.clone(), target.to_string(), text.to_string());
+
+        if !command(self, request)
+        {
+            return;
+        }
+
+        if self.channel.as_ref().map_or(false, |c| c.as_slice() == target)
+        {
+            self.add(vec![Message::new(time::get_time(), "me", text)]);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Handle a line typed by the user, returns false to quit
+    fn input(&mut self, line: &str) -> bool
+    {
+        let words: Vec<&str> = line.words().collect();
+
+        if words.is_empty()
+        {
+            return true;
+        }
+
+        if !words[0].starts_with("/")
+        {
+            match self.channel.clone()
+            {
+                Some(c) => self.send(c.as_slice(), line),
+                None    => self.add(vec![notice("No channel, use /join #channel")])
+            }
+            return true;
+        }
+
+        match words.as_slice()
+        {
+            ["/quit", ..] => return false,
+            ["/join", channel] =>
+            {
+                if command(self, Request::Join(self.network.clone(), channel.to_string()))
+                {
+                    self.switch(channel);
+                }
+            },
+            ["/part"] | ["/part", _] =>
+            {
+                let channel = match words.get(1)
+                    {
+                        Some(c) => Some(c.to_string()),
+                        None    => self.channel.clone()
+                    };
+
+                if let Some(c) = channel
+                {
+                    if command(self, Request::Part(self.network.clone(), c.clone())) &&
+                       self.channel == Some(c)
+                    {
+                        self.channel = None;
+                        self.messages.clear();
+                    }
+                }
+            },
+            ["/msg", target, ..] if words.len() > 2 =>
+            {
+                // keep the text as typed rather than rejoining the words
+                let text = line.splitn(2, ' ').nth(2).unwrap_or("").trim_left();
+                self.send(target, text);
+            },
+            ["/me", ..] if words.len() > 1 =>
+            {
+                let text = line.splitn(1, ' ').nth(1).unwrap_or("").trim_left();
+                match self.channel.clone()
+                {
+                    Some(c) => self.send(c.as_slice(), format!("\x01ACTION {}\x01", text).as_slice()),
+                    None    => self.add(vec![notice("No channel, use /join #channel")])
+                }
+            },
+            _ => self.add(vec![notice(format!("Unknown command: {}  (/join, /part, /msg, /me, /quit)",
+                                              line).as_slice())])
+        }
+
+        true
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A key typed by the user, returns the line once enter is pressed
+    fn key(&mut self, c: char) -> Option<String>
+    {
+        match c
+        {
+            '\n' | '\r' => return Some(::std::mem::replace(&mut self.typed, String::new())),
+            // backspace
+            '\x7f' | '\x08' => { self.typed.pop(); },
+            // ^U
+            '\x15' => self.typed.clear(),
+            // ^D on an empty line, or the end of the input
+            '\x04' if self.typed.is_empty() => return Some("/quit".to_string()),
+            c if !c.is_control() => self.typed.push(c),
+            _ => ()
+        }
+
+        None
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // (label, unread, mentions, current) for each line of the channel list
+    fn sidebar(&self) -> Vec<(String, uint, uint, bool)>
+    {
+        let mut entries = Vec::new();
+        let show_network = self.statuses.len() > 1;
+
+        for status in self.statuses.iter()
+        {
+            if show_network
+            {
+                entries.push((status.network.clone(), 0, 0, false));
+            }
+
+            let mut channels = status.channels.clone();
+            channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+            for c in channels.into_iter()
+            {
+                let current = self.channel.as_ref() == Some(&c.name) &&
+                              self.network.as_ref().map_or(true, |n| *n == status.network);
+                let label = if show_network { format!(" {}", c.name) } else { c.name };
+                entries.push((label, c.unread, c.mentions, current));
+            }
+        }
+
+        entries
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Messages are written first, letting the terminal scroll so the newest
+    // are at the bottom, then the channel list is drawn over the left margin
+    fn draw(&self)
+    {
+        let (rows, _) = self.size;
+
+        let mut t = term::stdout().unwrap();
+
+        (write!(t, "\x1b[2J\x1b[H")).unwrap();
+
+        // as many of the newest messages as fit above the prompt, counting the
+        // lines long messages are wrapped over
+        let mut start = self.messages.len();
+        let mut lines = 0;
+
+        while start > 0
+        {
+            let needed = super::message_lines(&self.messages[start - 1], SIDEBAR_WIDTH + 1);
+            if lines + needed > rows.saturating_sub(1)
+            {
+                break;
+            }
+
+            lines += needed;
+            start -= 1;
+        }
+
+        super::write_msgs(&mut t, &self.messages.slice_from(start).to_vec(),
+                          &self.highlights, SIDEBAR_WIDTH + 1);
+
+        let entries = self.sidebar();
+
+        for row in range(0, rows.saturating_sub(1))
+        {
+            (write!(t, "\x1b[{};1H{}", row + 1, String::from_char(SIDEBAR_WIDTH, ' '))).unwrap();
+            (write!(t, "\x1b[{};1H", row + 1)).unwrap();
+
+            if let Some(&(ref label, count, mentions, current)) = entries.get(row)
+            {
+                let count = if count > 0 { format!(" {}", count) } else { String::new() };
+                let width = SIDEBAR_WIDTH - cmp::min(count.len(), SIDEBAR_WIDTH);
+                let label: String = label.as_slice().chars().take(width).collect();
+
+                if current
+                {
+                    t.bg(term::color::BLUE).unwrap();
+                }
+                (write!(t, "{}", label)).unwrap();
+                t.fg(if mentions > 0 { term::color::RED } else { term::color::GREEN }).unwrap();
+                (write!(t, "{}", count)).unwrap();
+                t.reset().unwrap();
+            }
+
+            (write!(t, "\x1b[{};{}H|", row + 1, SIDEBAR_WIDTH + 1)).unwrap();
+        }
+
+        self.draw_prompt();
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The bottom line: the channel and what has been typed, as much of the
+    // end of it as fits
+    fn draw_prompt(&self)
+    {
+        let (rows, columns) = self.size;
+        let mut t = term::stdout().unwrap();
+
+        let prompt = match self.channel
+            {
+                Some(ref c) => format!("[{}] ", c),
+                None        => "[] ".to_string()
+            };
+
+        let room = columns - cmp::min(prompt.char_len() + 1, columns);
+        let typed: Vec<char> = self.typed.as_slice().chars().collect();
+        let shown: String = typed.slice_from(typed.len() - cmp::min(room, typed.len()))
+                                 .iter().map(|c| *c).collect();
+
+        (write!(t, "\x1b[{};1H\x1b[2K", rows)).unwrap();
+        t.fg(term::color::MAGENTA).unwrap();
+        (write!(t, "{}", prompt.as_slice().trim_right())).unwrap();
+        t.reset().unwrap();
+        (write!(t, " {}", shown)).unwrap();
+        t.flush().unwrap();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn run(reader: String, highlights: Vec<String>, strip: bool, server: super::Server)
+{
+    let capabilities = match super::connect(&server)
+        {
+            Ok((_, c)) => c,
+            Err(e)     => return super::fail(e)
+        };
+    let subscribe = capabilities.iter().any(|x| x.as_slice() == "event-time");
+    let keep_alive = capabilities.iter().any(|x| x.as_slice() == "keep-alive");
+
+    // keys typed by the user, read one at a time (the terminal neither
+    // buffering nor echoing them) so the line can be redrawn with the screen
+    let saved = stty(&["-g"]);
+    stty(&["-icanon", "-echo"]);
+
+    let (input_tx, input_rx) = channel();
+    let _input = input_tx.clone();
+    spawn(move ||
+          {
+              let mut stdin = io::stdin();
+              loop
+              {
+                  match stdin.read_char()
+                  {
+                      Ok(c)  => input_tx.send(c),
+                      Err(_) => break
+                  }
+              }
+              input_tx.send('\x04');
+          });
+
+    // something happened on one of the channels
+    let (event_tx, event_rx) = channel();
+    let _event = event_tx.clone();
+    if subscribe
+    {
+        let server = server.clone();
+        spawn(move ||
+              {
+                  let mut stream = match super::connect(&server)
+                      {
+                          Ok((s, _)) => s,
+                          Err(_)     => return
+                      };
+                  let id = circ_comms::next_id();
+                  if circ_comms::write_request(&mut stream, id,
+                                               &Request::Subscribe(None, Vec::new())).is_err()
+                  {
+                      return;
+                  }
+                  while super::read_response(&mut stream, id).is_ok()
+                  {
+                      event_tx.send(());
+                  }
+              });
+    }
+
+    // connection state changes aren't events, so check every so often, and
+    // poll when circd is too old to send events at all
+    let mut timer = Timer::new().unwrap();
+    let tick = timer.periodic(Duration::seconds(if subscribe { 30 } else { 2 }));
+
+    // the terminal may have been resized
+    let mut resize_timer = Timer::new().unwrap();
+    let resize = resize_timer.periodic(Duration::seconds(1));
+
+    let mut tui = Tui::new(server, reader, highlights, strip, keep_alive);
+
+    // use the alternate screen so the terminal is left as it was
+    print!("\x1b[?1049h");
+    io::stdio::flush();
+
+    tui.refresh();
+    tui.draw();
+
+    loop
+    {
+        select!(c = input_rx.recv() =>
+                match tui.key(c)
+                {
+                    Some(line) => if !tui.input(line.as_slice().trim_right()) { break },
+                    None       => { tui.draw_prompt(); continue }
+                },
+                () = event_rx.recv() => (),
+                () = tick.recv() => (),
+                () = resize.recv() => { if tui.resized() { tui.draw() } continue });
+
+        tui.refresh();
+        tui.draw();
+    }
+
+    print!("\x1b[?1049l");
+    io::stdio::flush();
+
+    match saved
+    {
+        Some(s) => { stty(&[s.as_slice().trim()]); },
+        None    => { stty(&["icanon", "echo"]); }
+    }
+}
@@ -2,46 +2,154 @@
 #![crate_type = "dylib"]
 
 ///////////////////////////////////////////////////////////////////////////////
+extern crate openssl;
 extern crate serialize;
 extern crate time;
 
 ///////////////////////////////////////////////////////////////////////////////
-use serialize::json;
+use serialize::{json, Decodable};
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::error::FromError;
+use std::fmt;
+use std::io::{IoError, IoResult};
 use std::io::net::pipe::UnixStream;
+use std::io::net::tcp::TcpStream;
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream};
+use openssl::ssl::{SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::x509::X509FileType;
 use std::os;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
 use time::Timespec;
 
 ///////////////////////////////////////////////////////////////////////////////
-pub fn address() -> String
+static NEXT_ID: AtomicUint = INIT_ATOMIC_UINT;
+
+///////////////////////////////////////////////////////////////////////////////
+// Id to send with a request.  Responses carry the id of the request they
+// answer (every event pushed to a subscriber has the id of the Subscribe).
+pub fn next_id() -> uint
+{
+    NEXT_ID.fetch_add(1, SeqCst) + 1
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Where the socket is when nothing else says: under the runtime directory
+// when there is one, otherwise ~/.circd
+fn default_socket() -> Path
 {
-    match os::getenv("HOME")
+    match (os::getenv("XDG_RUNTIME_DIR"), os::getenv("HOME"))
     {
-        Some(val) => format!("{}/.circd/circd-socket", val),
-        None      => format!("/tmp/circd-socket")
+        (Some(dir), _) => Path::new(dir).join_many(&["circd", "socket"]),
+        (None, Some(home)) => Path::new(home).join_many(&[".circd", "circd-socket"]),
+        (None, None) => Path::new("/tmp/circd/socket")
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-#[deriving(Show, Decodable, Encodable)]
+// The socket circ and circd talk over, taken from the --socket option, then
+// the CIRCD_SOCKET environment variable, then circd's configuration file
+pub fn socket_path(option: Option<String>, configured: Option<Path>) -> Path
+{
+    match option.or(os::getenv("CIRCD_SOCKET"))
+    {
+        Some(s) => Path::new(s),
+        None    => configured.unwrap_or_else(|| default_socket())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub static DEFAULT_READER: &'static str = "default";
+
+///////////////////////////////////////////////////////////////////////////////
+// Requests that refer to a channel carry an optional network name.  When the
+// network is None circd picks the only network that knows about the channel.
+// Unread messages are tracked separately for each reader id.  Subscribe keeps
+// the connection open and circd writes a Response::Event for everything that
+// happens on the channels (all channels when the list is empty) until the
+// client goes away.  ChangeNick changes the nick on every network.
+// Every connection starts with Hello, answered by a Response::Hello (or an
+// Error when the version isn't supported or the secret is wrong), before the
+// actual request.
+#[deriving(Show, Clone, Decodable, Encodable)]
 pub enum Request
 {
+    Hello
+    {
+        version:      uint,
+        capabilities: Vec<String>,
+        secret:       Option<String>
+    },
     ListChannels,
-    GetStatus,
-    GetMessages(String),
-    GetUsers(String),
-    Join(String),
-    Part(String),
-    SendMessage(String, String),
+    GetStatus(String),
+    GetMessages(Option<String>, String, String),
+    Peek(Option<String>, String, String),
+    GetUsers(Option<String>, String),
+    GetUsersStatus(Option<String>, String),
+    Join(Option<String>, String),
+    Part(Option<String>, String),
+    ChangeNick(String),
+    SendMessage(Option<String>, String, String),
+    SearchHistory
+    {
+        network: Option<String>,
+        channel: String,
+        pattern: String,
+        since:   Option<Timespec>,
+        until:   Option<Timespec>,
+        user:    Option<String>,
+        limit:   Option<uint>
+    },
+    Subscribe(Option<String>, Vec<String>),
     Quit
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-#[deriving(Show, Decodable, Encodable)]
+impl Request
+{
+    ///////////////////////////////////////////////////////////////////////////
+    // The capability circd has to have for the request to be understood
+    pub fn capability(&self) -> Option<&'static str>
+    {
+        match *self
+        {
+            // the status couldn't be decoded without how circd identified
+            Request::GetStatus(_)      => Some("auth"),
+            Request::Peek(_, _, _)     => Some("peek"),
+            Request::GetUsers(_, _)    => Some("users"),
+            Request::GetUsersStatus(_, _) => Some("users-status"),
+            Request::SearchHistory{..} => Some("search"),
+            // events couldn't be decoded without their time
+            Request::Subscribe(_, _)   => Some("event-time"),
+            Request::ChangeNick(_)     => Some("nick"),
+            _ => None
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A /me action, or any other CTCP request or reply with its command.  msg is
+// then only the text or parameters, without the \x01s.
+#[deriving(Show, Clone, PartialEq, Decodable, Encodable)]
+pub enum MessageKind
+{
+    Action,
+    Ctcp(String)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// kind is None for an ordinary message.  tags are the IRCv3 message tags the
+// server sent, None when there were none.  Both are None in history written
+// before they were kept.
+#[deriving(Show, Clone, Decodable, Encodable)]
 pub struct Message
 {
     pub time: Timespec,
     pub user: String,
-    pub msg:  String
+    pub msg:  String,
+    pub kind: Option<MessageKind>,
+    pub tags: Option<BTreeMap<String, String>>
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -49,85 +157,825 @@ impl Message
 {
     pub fn new(time: Timespec, user: &str, msg: &str) -> Message
     {
-        Message{time: time, user: user.to_string(), msg: msg.to_string()}
+        Message{time: time, user: user.to_string(), msg: msg.to_string(), kind: None, tags: None}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The text of a /me action, if the message is one.  Older history keeps
+    // actions as the raw CTCP.
+    pub fn action(&self) -> Option<&str>
+    {
+        let msg = self.msg.as_slice();
+
+        match self.kind
+        {
+            Some(MessageKind::Action) => Some(msg),
+            Some(_) => None,
+            None if msg.starts_with("\x01ACTION ") && msg.ends_with("\x01") =>
+                Some(msg.slice(8, msg.len() - 1)),
+            None => None
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Something happening on a channel, pushed to subscribed clients.  The first
+// field is always the channel name and the last when it happened, by the
+// server's clock (a message carries its own time).  The time was added
+// after the rest; older clients decode the fields they know and leave it.
+#[deriving(Show, Clone, Decodable, Encodable)]
+pub enum Event
+{
+    Message(String, Message),
+    Join(String, String, Timespec),
+    Part(String, String, Timespec),
+    Quit(String, String, Timespec),
+    Topic(String, String, String, Timespec),
+    Nick(String, String, String, Timespec)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Event
+{
+    pub fn channel(&self) -> &str
+    {
+        match *self
+        {
+            Event::Message(ref c, _)     => c.as_slice(),
+            Event::Join(ref c, _, _)     => c.as_slice(),
+            Event::Part(ref c, _, _)     => c.as_slice(),
+            Event::Quit(ref c, _, _)     => c.as_slice(),
+            Event::Topic(ref c, _, _, _) => c.as_slice(),
+            Event::Nick(ref c, _, _, _)  => c.as_slice()
+        }
+    }
+
+    pub fn time(&self) -> Timespec
+    {
+        match *self
+        {
+            Event::Message(_, ref m)  => m.time,
+            Event::Join(_, _, t)      => t,
+            Event::Part(_, _, t)      => t,
+            Event::Quit(_, _, t)      => t,
+            Event::Topic(_, _, _, t)  => t,
+            Event::Nick(_, _, _, t)   => t
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn has_prefix(name: &str) -> bool
+{
+    name.starts_with("#") || name.starts_with("&")
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What a channel name is resolved for
+#[deriving(Clone, PartialEq)]
+pub enum ChannelUse
+{
+    Reading,
+    Joining,
+    Sending
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Find the channel meant by a name given without its # or &, from the
+// channels circd reports in Response::Channels (for network only, when
+// given).  A query buffer or channel of exactly that name comes first, then
+// the channel with the name after its # or &, then a channel it is a unique
+// prefix of.  A name matching nothing is taken as a nick, unless joining, when
+// it is taken as a new # channel.  A message is never sent to a channel the
+// name is only a prefix of, as the name is then more likely a nick.
+pub fn resolve_channel(name: &str,
+                       network: Option<&str>,
+                       channels: &Vec<(String, Vec<String>)>,
+                       purpose: ChannelUse) -> Result<String, String>
+{
+    if has_prefix(name)
+    {
+        return Ok(name.to_string());
+    }
+
+    let mut known: Vec<&str> = channels.iter()
+        .filter(|&&(ref n, _)| network.map_or(true, |x| n.as_slice() == x))
+        .flat_map(|&(_, ref c)| c.iter().map(|s| s.as_slice()))
+        .collect();
+    known.sort();
+    known.dedup();
+
+    let lower = name.to_lowercase();
+
+    if let Some(c) = known.iter().find(|c| c.to_lowercase() == lower)
+    {
+        return Ok(c.to_string());
+    }
+
+    let unprefixed = |c: &&str| -> String
+    {
+        if has_prefix(*c) { c.slice_from(1).to_lowercase() } else { String::new() }
+    };
+
+    let exact: Vec<&&str> = known.iter().filter(|c| unprefixed(*c) == lower).collect();
+    let candidates = if !exact.is_empty() || purpose == ChannelUse::Sending
+        {
+            exact
+        }
+        else
+        {
+            known.iter().filter(|c| unprefixed(*c).as_slice().starts_with(lower.as_slice())).collect()
+        };
+
+    match candidates.len()
+    {
+        1 => Ok(candidates[0].to_string()),
+        0 if purpose == ChannelUse::Joining => Ok(format!("#{}", name)),
+        0 => Ok(name.to_string()),
+        _ => Err(format!("{} could be any of {}", name,
+                         candidates.iter().map(|c| c.to_string()).collect::<Vec<String>>().connect(", ")))
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
+// Fill in the {placeholders} of a template in one pass, so text substituted
+// for one (a message containing "{nick}", say) is never expanded itself.
+// Anything else in braces is left alone.
+pub fn substitute(template: &str, values: &[(&str, &str)]) -> String
+{
+    let mut result = String::new();
+    let mut rest = template;
+
+    loop
+    {
+        let start = match rest.find('{')
+            {
+                Some(i) => i,
+                None    => { result.push_str(rest); return result }
+            };
+
+        result.push_str(rest.slice_to(start));
+        rest = rest.slice_from(start);
+
+        match values.iter().find(|&&(name, _)| rest.starts_with(name))
+        {
+            Some(&(name, value)) =>
+            {
+                result.push_str(value);
+                rest = rest.slice_from(name.len());
+            },
+            None =>
+            {
+                result.push('{');
+                rest = rest.slice_from(1);
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Reconnecting holds the number of failed attempts and when the next one is due
+#[deriving(Show, Clone, PartialEq, Decodable, Encodable)]
+pub enum ConnectionState
+{
+    Connected,
+    Reconnecting(uint, Timespec)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Whether circd has identified with the network, through SASL or NickServ.
+// Anonymous when no account is configured, Identified holds the account and
+// Failed the reason given.
+#[deriving(Show, Clone, PartialEq, Decodable, Encodable)]
+pub enum AuthState
+{
+    Anonymous,
+    Pending,
+    Identified(String),
+    Failed(String)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Unread messages of one channel for a reader, and how many of them mention
+// the user
+#[deriving(Show, Clone, Decodable, Encodable)]
+pub struct ChannelStatus
+{
+    pub name:     String,
+    pub unread:   uint,
+    pub mentions: uint
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Show, Clone, Decodable, Encodable)]
+pub struct NetworkStatus
+{
+    pub network:    String,
+    pub connection: ConnectionState,
+    pub auth:       AuthState,
+    pub channels:   Vec<ChannelStatus>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl NetworkStatus
+{
+    pub fn new(network: &str, connection: ConnectionState, auth: AuthState) -> NetworkStatus
+    {
+        NetworkStatus{network: network.to_string(), connection: connection, auth: auth,
+                      channels: Vec::new()}
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A channel member: the nick with its highest mode prefix, the away message
+// if they are away and the account they are logged in to, as far as circd
+// has been told
+#[deriving(Show, Clone, Decodable, Encodable)]
+pub struct UserStatus
+{
+    pub name:    String,
+    pub away:    Option<String>,
+    pub account: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Every response that refers to a channel carries the name of the network
+// the channel belongs to.
 #[deriving(Show, Decodable, Encodable)]
 pub enum Response
 {
-    Channels(Vec<String>),
-    Status(Vec<(String, uint)>),
-    Messages(Vec<Message>),
-    Users(Vec<String>),
-    Error(String)
+    Hello
+    {
+        version:      uint,
+        capabilities: Vec<String>
+    },
+    Channels(Vec<(String, Vec<String>)>),
+    Status(Vec<NetworkStatus>),
+    Messages(String, Vec<Message>),
+    Users(String, Vec<String>),
+    Event(String, Event),
+    Error(String),
+    UsersStatus(String, Vec<UserStatus>)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Version of the protocol, agreed on by the Hello exchange.  Version 3 added
+// authentication to the status, 4 the tags of messages and 5 the kind of
+// actions and CTCP messages.
+pub static PROTOCOL_VERSION: uint = 5;
+
+// Oldest version still understood, version 2 added mentions to the status.
+// Older clients ignore the fields added since.  Newer clients rely on them
+// only when circd has the capability that goes with them, or they are
+// Options that are None when missing, like the kind and tags of a message.
+pub static MIN_PROTOCOL_VERSION: uint = 2;
+
+// Optional features, sent in the Hello exchange so clients can tell what the
+// daemon they are talking to supports.  With keep-alive a connection stays
+// open for further requests after a request that is answered.
+pub static CAPABILITIES: &'static [&'static str] = &["auth", "event-time", "keep-alive", "nick", "peek",
+                                                    "search", "subscribe", "users", "users-status"];
+
+// Largest frame accepted, anything bigger is assumed to be garbage
+pub static MAX_FRAME_SIZE: uint = 16 * 1024 * 1024;
+
+///////////////////////////////////////////////////////////////////////////////
+pub enum CommsError
+{
+    Io(IoError),
+    Utf8,
+    Json(String),
+    FrameTooLarge(uint),
+    VersionMismatch(uint),
+    Protocol(String),
+    Refused(String),
+    Tls(String)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl fmt::Show for CommsError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            CommsError::Io(ref e) => write!(f, "{}", e),
+            CommsError::Utf8 => write!(f, "Message isn't valid UTF-8"),
+            CommsError::Json(ref e) => write!(f, "Unable to decode message: {}", e),
+            CommsError::FrameTooLarge(len) =>
+                write!(f, "Message of {} bytes is larger than the limit of {}", len, MAX_FRAME_SIZE),
+            CommsError::VersionMismatch(v) =>
+                write!(f, "Protocol version {} isn't supported (expected {} to {}), are circ and circd the same version?",
+                       v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            CommsError::Protocol(ref e) => write!(f, "Protocol error: {}", e),
+            CommsError::Refused(ref e) => write!(f, "{}", e),
+            CommsError::Tls(ref e) => write!(f, "TLS error: {}", e)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl FromError<IoError> for CommsError
+{
+    fn from_error(e: IoError) -> CommsError
+    {
+        CommsError::Io(e)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Certificates for a TLS connection.  circd needs a certificate and key and
+// only accepts clients with a certificate signed by ca when one is given.
+// A client always checks the certificate of the server, against ca or the
+// system's certificates without one, and sends its own when it has one.
+#[deriving(Clone)]
+pub struct TlsSettings
+{
+    pub certificate: Option<Path>,
+    pub key: Option<Path>,
+    pub ca: Option<Path>
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn decode_request(data: &str) -> Option<Request>
+impl TlsSettings
 {
-    match json::decode::<Request>(data.as_slice())
+    ///////////////////////////////////////////////////////////////////////////
+    // A server with a ca refuses clients without a certificate, as that is
+    // how they log in.  A client refuses any server it can't verify.
+    pub fn context(&self, server: bool) -> Result<SslContext, CommsError>
     {
-        Ok(o)  => Some(o),
-        Err(e) => { println!("JSON decoding error: {}", e); None }
+        let mut context = match SslContext::new(SslMethod::Sslv23)
+            {
+                Ok(c)  => c,
+                Err(e) => return Err(CommsError::Tls(format!("{}", e)))
+            };
+
+        if let Some(ref c) = self.certificate
+        {
+            if let Some(e) = context.set_certificate_file(c, X509FileType::PEM)
+            {
+                return Err(CommsError::Tls(format!("Unable to load {}: {}", c.display(), e)));
+            }
+        }
+
+        if let Some(ref k) = self.key
+        {
+            if let Some(e) = context.set_private_key_file(k, X509FileType::PEM)
+            {
+                return Err(CommsError::Tls(format!("Unable to load {}: {}", k.display(), e)));
+            }
+        }
+
+        if let Some(ref ca) = self.ca
+        {
+            if let Some(e) = context.set_CA_file(ca)
+            {
+                return Err(CommsError::Tls(format!("Unable to load {}: {}", ca.display(), e)));
+            }
+        }
+        else if !server
+        {
+            if let Some(e) = context.set_default_verify_paths()
+            {
+                return Err(CommsError::Tls(format!("Unable to load the system certificates: {}", e)));
+            }
+        }
+
+        if !server
+        {
+            context.set_verify(SSL_VERIFY_PEER, None);
+        }
+        else if self.ca.is_some()
+        {
+            context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+        }
+
+        Ok(context)
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn decode_response(data: &str) -> Response
+// The client side of a TLS connection to host.  A certificate that verifies
+// is no use unless it is for the host, by its common name, which may start
+// with *. for any one name in a domain.
+pub fn connect_tls(context: &SslContext, stream: TcpStream, host: &str) -> Result<SslStream<TcpStream>, CommsError>
 {
-    let response: Response = match json::decode(data.as_slice())
+    let stream = match SslStream::new(context, stream)
         {
-            Ok(o)  => o,
-            Err(e) => panic!("JSON decoding error: {}", e)
+            Ok(s)  => s,
+            Err(e) => return Err(CommsError::Tls(format!("{}", e)))
         };
-    
-    response
+
+    let name = match stream.get_peer_certificate()
+        {
+            Some(c) => c.subject_name().text_by_nid(Nid::CN).map(|n| n.to_string()),
+            None    => None
+        };
+
+    match name
+    {
+        Some(ref n) if certificate_names(n.as_slice(), host) => Ok(stream),
+        Some(n) => Err(CommsError::Tls(format!("The certificate is for {}, not {}", n, host))),
+        None    => Err(CommsError::Tls(format!("The certificate has no name to check against {}", host)))
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-pub fn read_request(stream: &mut UnixStream) -> Option<Request>
+fn certificate_names(name: &str, host: &str) -> bool
 {
-    let len = stream.read_be_uint().unwrap();
-    let data = stream.read_exact(len).unwrap();
+    let name = name.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
 
-    match ::std::str::from_utf8(data.as_slice())
+    if name.as_slice().starts_with("*.")
     {
-        Some(s) => decode_request(s),
-        None => { println!("Failed to read string from data"); None }
+        match host.as_slice().find('.')
+        {
+            Some(i) => host.as_slice().slice_from(i) == name.as_slice().slice_from(1),
+            None    => false
+        }
+    }
+    else
+    {
+        name == host
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-pub fn write_request(stream: &mut UnixStream, request: &Request)
+// A connection between circ and circd, either the local socket or over the
+// network
+pub enum Transport
 {
-    let string =  json::encode(&request);
-    let data = string.as_bytes();
-    
-    stream.write_be_uint(data.len()).unwrap();
-    stream.write(data).unwrap();
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    Tls(SslStream<TcpStream>)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Transport
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn connect_unix(path: &Path) -> Result<Transport, CommsError>
+    {
+        Ok(Transport::Unix(try!(UnixStream::connect(path))))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // address is host:port, TLS is used when settings are given
+    pub fn connect_tcp(address: &str, tls: Option<&TlsSettings>) -> Result<Transport, CommsError>
+    {
+        let stream = try!(TcpStream::connect(address));
+
+        match tls
+        {
+            Some(t) =>
+            {
+                let host = match address.rfind(':')
+                    {
+                        Some(i) => address.slice_to(i),
+                        None    => address
+                    };
+                let host = host.trim_left_chars('[').trim_right_chars(']');
+
+                let context = try!(t.context(false));
+                Ok(Transport::Tls(try!(connect_tls(&context, stream, host))))
+            },
+            None => Ok(Transport::Tcp(stream))
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Server side of a TLS connection accepted by circd
+    pub fn accept_tls(stream: TcpStream, context: &SslContext) -> Result<Transport, CommsError>
+    {
+        match SslStream::new_server(context, stream)
+        {
+            Ok(s)  => Ok(Transport::Tls(s)),
+            Err(e) => Err(CommsError::Tls(format!("{}", e)))
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn set_read_timeout(&mut self, timeout: Option<u64>)
+    {
+        match *self
+        {
+            Transport::Unix(ref mut s) => s.set_read_timeout(timeout),
+            Transport::Tcp(ref mut s)  => s.set_read_timeout(timeout),
+            Transport::Tls(ref mut s)  => s.get_inner().set_read_timeout(timeout)
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn set_write_timeout(&mut self, timeout: Option<u64>)
+    {
+        match *self
+        {
+            Transport::Unix(ref mut s) => s.set_write_timeout(timeout),
+            Transport::Tcp(ref mut s)  => s.set_write_timeout(timeout),
+            Transport::Tls(ref mut s)  => s.get_inner().set_write_timeout(timeout)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Reader for Transport
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint>
+    {
+        match *self
+        {
+            Transport::Unix(ref mut s) => s.read(buf),
+            Transport::Tcp(ref mut s)  => s.read(buf),
+            Transport::Tls(ref mut s)  => s.read(buf)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Writer for Transport
+{
+    fn write(&mut self, buf: &[u8]) -> IoResult<()>
+    {
+        match *self
+        {
+            Transport::Unix(ref mut s) => s.write(buf),
+            Transport::Tcp(ref mut s)  => s.write(buf),
+            Transport::Tls(ref mut s)  => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()>
+    {
+        match *self
+        {
+            Transport::Unix(ref mut s) => s.flush(),
+            Transport::Tcp(ref mut s)  => s.flush(),
+            Transport::Tls(ref mut s)  => s.flush()
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What is actually sent: the message along with its correlation id
+#[deriving(Decodable, Encodable)]
+struct RequestFrame
+{
+    id: uint,
+    request: Request
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable, Encodable)]
+struct ResponseFrame
+{
+    id: uint,
+    response: Response
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-pub fn read_response(stream: &mut UnixStream) -> Response
+fn decode<T: Decodable<json::Decoder, json::DecoderError>>(data: &str) -> Result<T, CommsError>
 {
-    let len = stream.read_be_uint().unwrap();
-    let data = stream.read_exact(len).unwrap();
+    match json::decode(data)
+    {
+        Ok(o)  => Ok(o),
+        Err(e) => Err(CommsError::Json(format!("{}", e)))
+    }
+}
 
-    let string = ::std::str::from_utf8(data.as_slice()).unwrap();
+///////////////////////////////////////////////////////////////////////////////
+fn read_frame<R: Reader>(stream: &mut R) -> Result<String, CommsError>
+{
+    let len = try!(stream.read_be_uint());
+    if len > MAX_FRAME_SIZE
+    {
+        return Err(CommsError::FrameTooLarge(len));
+    }
 
-    decode_response(string)
+    let data = try!(stream.read_exact(len));
+
+    match String::from_utf8(data)
+    {
+        Ok(s)  => Ok(s),
+        Err(_) => Err(CommsError::Utf8)
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-pub fn write_response(stream: &mut UnixStream, response: Response)
+fn write_frame<W: Writer>(stream: &mut W, string: String) -> Result<(), CommsError>
 {
-    let string =  json::encode(&response);
     let data = string.as_bytes();
     
-    stream.write_be_uint(data.len()).unwrap();
-    stream.write(data).unwrap();
+    try!(stream.write_be_uint(data.len()));
+    try!(stream.write(data));
+    try!(stream.flush());
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn read_request<R: Reader>(stream: &mut R) -> Result<(uint, Request), CommsError>
+{
+    let data = try!(read_frame(stream));
+    let frame: RequestFrame = try!(decode(data.as_slice()));
+
+    Ok((frame.id, frame.request))
 }
 
+///////////////////////////////////////////////////////////////////////////////
+pub fn write_request<W: Writer>(stream: &mut W, id: uint, request: &Request) -> Result<(), CommsError>
+{
+    write_frame(stream, json::encode(&RequestFrame{id: id, request: request.clone()}))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn read_response<R: Reader>(stream: &mut R) -> Result<(uint, Response), CommsError>
+{
+    let data = try!(read_frame(stream));
+    let frame: ResponseFrame = try!(decode(data.as_slice()));
+
+    Ok((frame.id, frame.response))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn write_response<W: Writer>(stream: &mut W, id: uint, response: Response) -> Result<(), CommsError>
+{
+    write_frame(stream, json::encode(&ResponseFrame{id: id, response: response}))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn capabilities() -> Vec<String>
+{
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn supported(version: uint) -> bool
+{
+    version >= MIN_PROTOCOL_VERSION && version <= PROTOCOL_VERSION
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Compare secrets without giving away how much of the guess was right
+fn same_secret(a: &str, b: &str) -> bool
+{
+    a.len() == b.len() &&
+        a.bytes().zip(b.bytes()).fold(0u8, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Client side of the handshake, returns the capabilities of circd
+pub fn client_hello<S: Reader + Writer>(stream: &mut S, secret: Option<String>)
+    -> Result<Vec<String>, CommsError>
+{
+    let id = next_id();
+    try!(write_request(stream, id, &Request::Hello{version: PROTOCOL_VERSION,
+                                                   capabilities: capabilities(),
+                                                   secret: secret}));
+
+    match try!(read_response(stream))
+    {
+        (_, Response::Hello{version, capabilities}) =>
+            if supported(version) { Ok(capabilities) } else { Err(CommsError::VersionMismatch(version)) },
+        (_, Response::Error(e)) => Err(CommsError::Refused(e)),
+        (_, r) => Err(CommsError::Protocol(format!("Expected Hello, received {}", r)))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Daemon side of the handshake, returns the capabilities of the client.  A
+// client with an unsupported version or without the secret (when there is
+// one) is sent an Error explaining why.
+pub fn server_hello<S: Reader + Writer>(stream: &mut S, secret: Option<&str>)
+    -> Result<Vec<String>, CommsError>
+{
+    let (id, request) = try!(read_request(stream));
+
+    match request
+    {
+        Request::Hello{version, capabilities: client, secret: given} =>
+            if !secret.map_or(true, |s| given.map_or(false, |g| same_secret(s, g.as_slice())))
+            {
+                let error = "Authentication failed".to_string();
+                try!(write_response(stream, id, Response::Error(error.clone())));
+                Err(CommsError::Refused(error))
+            }
+            else if supported(version)
+            {
+                try!(write_response(stream, id, Response::Hello{version: PROTOCOL_VERSION,
+                                                                capabilities: capabilities()}));
+                Ok(client)
+            }
+            else
+            {
+                let error = format!("circd supports protocol versions {} to {}, not {}; upgrade circ or restart circd",
+                                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, version);
+                try!(write_response(stream, id, Response::Error(error)));
+                Err(CommsError::VersionMismatch(version))
+            },
+        r =>
+        {
+            let error = "Expected Hello, circ is probably older than circd".to_string();
+            try!(write_response(stream, id, Response::Error(error)));
+            Err(CommsError::Protocol(format!("Expected Hello, received {}", r)))
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{ChannelUse, resolve_channel, substitute};
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn channels() -> Vec<(String, Vec<String>)>
+    {
+        let network = |name: &str, channels: &[&str]|
+            (name.to_string(), channels.iter().map(|c| c.to_string()).collect());
+
+        vec![network("libera", &["#rust", "#rust-beginners", "&local", "bob"]),
+             network("oftc", &["#debian"])]
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn resolve(name: &str, network: Option<&str>, purpose: ChannelUse) -> Result<String, String>
+    {
+        resolve_channel(name, network, &channels(), purpose)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn keeps_names_with_a_prefix()
+    {
+        assert_eq!(resolve("#new", None, ChannelUse::Reading), Ok("#new".to_string()));
+        assert_eq!(resolve("&new", None, ChannelUse::Sending), Ok("&new".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn finds_a_bare_name()
+    {
+        assert_eq!(resolve("bob", None, ChannelUse::Reading), Ok("bob".to_string()));
+        assert_eq!(resolve("rust", None, ChannelUse::Sending), Ok("#rust".to_string()));
+        assert_eq!(resolve("local", None, ChannelUse::Reading), Ok("&local".to_string()));
+        assert_eq!(resolve("RUST", None, ChannelUse::Reading), Ok("#rust".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn finds_a_unique_prefix_unless_sending()
+    {
+        assert_eq!(resolve("deb", None, ChannelUse::Reading), Ok("#debian".to_string()));
+        assert_eq!(resolve("deb", None, ChannelUse::Sending), Ok("deb".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn refuses_an_ambiguous_prefix()
+    {
+        assert_eq!(resolve("ru", None, ChannelUse::Reading),
+                   Err("ru could be any of #rust, #rust-beginners".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn takes_an_unknown_name_as_a_nick_or_new_channel()
+    {
+        assert_eq!(resolve("alice", None, ChannelUse::Reading), Ok("alice".to_string()));
+        assert_eq!(resolve("new", None, ChannelUse::Joining), Ok("#new".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn only_looks_at_the_given_network()
+    {
+        assert_eq!(resolve("debian", Some("libera"), ChannelUse::Reading), Ok("debian".to_string()));
+        assert_eq!(resolve("debian", Some("oftc"), ChannelUse::Reading), Ok("#debian".to_string()));
+    }
 
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn substitutes_each_placeholder()
+    {
+        assert_eq!(substitute("[{time}] <{nick}> {message}",
+                              &[("{time}", "12:00"), ("{nick}", "bob"), ("{message}", "hi")]),
+                   "[12:00] <bob> hi".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn leaves_substituted_text_alone()
+    {
+        assert_eq!(substitute("<{nick}> {message}",
+                              &[("{nick}", "{message}"), ("{message}", "try {nick} {time}")]),
+                   "<{message}> try {nick} {time}".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn keeps_unknown_braces()
+    {
+        assert_eq!(substitute("{other} {nick} {", &[("{nick}", "bob")]),
+                   "{other} bob {".to_string());
+    }
+}
@@ -0,0 +1,293 @@
+///////////////////////////////////////////////////////////////////////////////
+use serialize::base64::{STANDARD, ToBase64};
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use circ_comms::AuthState;
+use config::{Credentials, Mechanism};
+use link::Link;
+
+use irc::data::Message;
+use irc::data::Command::PRIVMSG;
+
+///////////////////////////////////////////////////////////////////////////////
+// AUTHENTICATE arguments longer than this are split over several messages
+static SASL_CHUNK: uint = 400;
+
+///////////////////////////////////////////////////////////////////////////////
+// Seconds to wait for NickServ to answer IDENTIFY, and after it has said yes
+// for the RPL_LOGGEDIN that gives the account
+static NICKSERV_TIMEOUT: i64 = 30;
+static LOGGEDIN_WAIT: i64 = 5;
+
+///////////////////////////////////////////////////////////////////////////////
+// Identifying with a network.  With SASL the server holds registration back
+// until CAP END, which is sent once SASL has succeeded or failed.  When it
+// fails, or isn't used, NickServ is asked once registration has finished.
+// Its answer has to arrive by the deadline.
+pub struct Auth
+{
+    credentials: Option<Credentials>,
+    state: AuthState,
+    nickserv_asked: bool,
+    nickserv_accepted: bool,
+    deadline: Timespec
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn first_param(msg: &Message) -> Option<&str>
+{
+    match msg.args.as_slice().first()
+    {
+        Some(a) => Some(a.as_slice()),
+        None    => msg.suffix.as_ref().map(|s| s.as_slice())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Auth
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(credentials: Option<Credentials>) -> Auth
+    {
+        let mut auth = Auth{credentials: credentials, state: AuthState::Anonymous,
+                            nickserv_asked: false, nickserv_accepted: false,
+                            deadline: Timespec::new(0, 0)};
+        auth.reset();
+        auth
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A new connection has been made, identifying starts again
+    pub fn reset(&mut self)
+    {
+        self.state = if self.credentials.is_some() { AuthState::Pending } else { AuthState::Anonymous };
+        self.nickserv_asked = false;
+        self.nickserv_accepted = false;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn state(&self) -> AuthState
+    {
+        self.state.clone()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn identified(&mut self, account: String)
+    {
+        println!("Identified as {}", account);
+        self.state = AuthState::Identified(account);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn failed(&mut self, reason: String)
+    {
+        println!("Unable to identify: {}", reason);
+        self.state = AuthState::Failed(reason);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn wants_sasl(&self) -> bool
+    {
+        self.credentials.as_ref().map_or(false, |c| c.sasl.is_some())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The server has granted the sasl capability
+    pub fn start(&self, link: &Link)
+    {
+        let mechanism = match self.credentials.as_ref().and_then(|c| c.sasl.clone())
+            {
+                Some(Mechanism::External) => "EXTERNAL",
+                _                         => "PLAIN"
+            };
+
+        link.send_raw("AUTHENTICATE", vec![mechanism], None);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The server doesn't offer SASL, or refused it
+    pub fn unavailable(&mut self)
+    {
+        self.fall_back("The server doesn't support SASL".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Let registration finish, leaving NickServ to try if it may
+    fn sasl_failed(&mut self, link: &Link, reason: String)
+    {
+        link.send_raw("CAP", vec!["END"], None);
+        self.fall_back(reason);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn fall_back(&mut self, reason: String)
+    {
+        if self.credentials.as_ref().map_or(false, |c| c.nickserv)
+        {
+            println!("SASL failed, trying NickServ: {}", reason);
+        }
+        else
+        {
+            self.failed(reason);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // account\0account\0password in base64, as PLAIN wants
+    fn send_plain(&self, link: &Link)
+    {
+        let (account, password) = match self.credentials
+            {
+                Some(Credentials{ref account, password: Some(ref password), ..}) => (account, password),
+                _ => return
+            };
+
+        let response = format!("{}\0{}\0{}", account, account, password).as_bytes().to_base64(STANDARD);
+        let response = response.as_slice();
+
+        let mut start = 0;
+        while start < response.len()
+        {
+            let end = if start + SASL_CHUNK < response.len() { start + SASL_CHUNK } else { response.len() };
+            link.send_raw("AUTHENTICATE", vec![response.slice(start, end)], None);
+            start = end;
+        }
+
+        // an empty message ends a response that filled its last chunk
+        if response.len() % SASL_CHUNK == 0
+        {
+            link.send_raw("AUTHENTICATE", vec!["+"], None);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // AUTHENTICATE and the SASL numerics, the capability itself is
+    // negotiated by Capabilities
+    pub fn handle(&mut self, link: &Link, msg: &Message)
+    {
+        let mechanism = match self.credentials
+            {
+                Some(ref c) => c.sasl.clone(),
+                None        => return
+            };
+
+        match msg.command.as_slice()
+        {
+            "AUTHENTICATE" if first_param(msg) == Some("+") =>
+                match mechanism
+                {
+                    Some(Mechanism::External) => { link.send_raw("AUTHENTICATE", vec!["+"], None); },
+                    _ => self.send_plain(link)
+                },
+            // RPL_LOGGEDIN: <nick> <nick!user@host> <account> :You are now logged in as <account>
+            "900" =>
+                if let Some(account) = msg.args.as_slice().get(2)
+                {
+                    self.identified(account.clone());
+                },
+            // RPL_SASLSUCCESS
+            "903" =>
+            {
+                link.send_raw("CAP", vec!["END"], None);
+                if self.state == AuthState::Pending
+                {
+                    let account = self.credentials.as_ref().unwrap().account.clone();
+                    self.identified(account);
+                }
+            },
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED
+            "902" | "904" | "905" | "906" =>
+            {
+                let reason = msg.suffix.clone().unwrap_or("SASL authentication failed".to_string());
+                self.sasl_failed(link, reason);
+            },
+            _ => ()
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Registration has finished, so SASL is over one way or the other
+    pub fn registered(&mut self, link: &Link)
+    {
+        if self.state != AuthState::Pending
+        {
+            return;
+        }
+
+        let identify = match self.credentials
+            {
+                Some(Credentials{ref account, password: Some(ref password), nickserv: true, ..}) =>
+                    Some(format!("IDENTIFY {} {}", account, password)),
+                _ => None
+            };
+
+        match identify
+        {
+            Some(i) =>
+            {
+                link.send(PRIVMSG("NickServ", i.as_slice()));
+                self.nickserv_asked = true;
+                self.deadline = time::get_time() + Duration::seconds(NICKSERV_TIMEOUT);
+            },
+            None => self.failed("The server finished registration without SASL".to_string())
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // NickServ's answer.  Services that send RPL_LOGGEDIN are identified by
+    // handle() with the account it gives, the text of the notice is only
+    // believed when that doesn't come.
+    pub fn notice(&mut self, msg: &Message)
+    {
+        if !self.nickserv_asked || self.state != AuthState::Pending
+        {
+            return;
+        }
+
+        if !msg.prefix.as_ref().map_or(false, |p| p.as_slice().to_lowercase().starts_with("nickserv!"))
+        {
+            return;
+        }
+
+        let text = match msg.suffix { Some(ref s) => s.clone(), None => return };
+        let lower = text.as_slice().to_lowercase();
+
+        if lower.contains("you are now identified") || lower.contains("password accepted")
+        {
+            if !self.nickserv_accepted
+            {
+                self.nickserv_accepted = true;
+                self.deadline = time::get_time() + Duration::seconds(LOGGEDIN_WAIT);
+            }
+        }
+        else if lower.contains("invalid password") || lower.contains("incorrect") ||
+                lower.contains("not registered") || lower.contains("isn't registered")
+        {
+            self.failed(text);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Called regularly to give up on NickServ, or take its word for it,
+    // once the deadline has passed
+    pub fn tick(&mut self)
+    {
+        if !self.nickserv_asked || self.state != AuthState::Pending || time::get_time() < self.deadline
+        {
+            return;
+        }
+
+        if self.nickserv_accepted
+        {
+            let account = self.credentials.as_ref().unwrap().account.clone();
+            self.identified(account);
+        }
+        else
+        {
+            self.failed("no answer from NickServ".to_string());
+        }
+    }
+}
@@ -0,0 +1,113 @@
+///////////////////////////////////////////////////////////////////////////////
+use link::Link;
+
+use irc::data::Message;
+
+///////////////////////////////////////////////////////////////////////////////
+// IRCv3 capabilities asked for whenever the server offers them
+static WANTED: &'static [&'static str] =
+    &["server-time", "echo-message", "away-notify", "account-notify", "message-tags"];
+
+///////////////////////////////////////////////////////////////////////////////
+// CAP negotiation.  Link sends CAP LS ahead of PASS, NICK and USER, which
+// holds registration back until CAP END.  That is sent here, once, unless
+// SASL was granted, in which case Auth sends it once SASL is over.
+pub struct Capabilities
+{
+    offered: Vec<String>,
+    enabled: Vec<String>,
+    sasl_requested: bool
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Capabilities
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> Capabilities
+    {
+        Capabilities{offered: Vec::new(), enabled: Vec::new(), sasl_requested: false}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A new connection negotiates from scratch
+    pub fn reset(&mut self)
+    {
+        self.offered.clear();
+        self.enabled.clear();
+        self.sasl_requested = false;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn has(&self, name: &str) -> bool
+    {
+        self.enabled.iter().any(|c| c.as_slice() == name)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // CAP <nick> LS|ACK|NAK [*] :<capabilities>.  When sasl is wanted the
+    // result is whether it can be used, once that is known.
+    pub fn handle(&mut self, link: &Link, msg: &Message, sasl: bool) -> Option<bool>
+    {
+        let caps: Vec<String> = match msg.suffix
+            {
+                Some(ref s) => s.as_slice().words().map(|c| c.to_string()).collect(),
+                None        => Vec::new()
+            };
+        let args: Vec<&str> = msg.args.iter().map(|a| a.as_slice()).collect();
+
+        match args.as_slice().get(1).map(|a| *a)
+        {
+            Some("LS") =>
+            {
+                // values such as sasl=PLAIN,EXTERNAL aren't needed
+                self.offered.extend(caps.iter().map(|c| c.as_slice().split('=').next().unwrap().to_string()));
+
+                // a * before the list means more lines are coming
+                if args.as_slice().get(2) == Some(&"*")
+                {
+                    return None;
+                }
+
+                let mut request: Vec<&str> =
+                    WANTED.iter().map(|w| *w).filter(|w| self.offered.iter().any(|o| o.as_slice() == *w)).collect();
+
+                self.sasl_requested = sasl && self.offered.iter().any(|o| o.as_slice() == "sasl");
+                if self.sasl_requested
+                {
+                    request.push("sasl");
+                }
+
+                if request.is_empty()
+                {
+                    link.send_raw("CAP", vec!["END"], None);
+                }
+                else
+                {
+                    link.send_raw("CAP", vec!["REQ"], Some(request.connect(" ").as_slice()));
+                }
+
+                if sasl && !self.sasl_requested { Some(false) } else { None }
+            },
+            Some("ACK") =>
+            {
+                // a - in front means disabled
+                self.enabled.extend(caps.iter().filter(|c| !c.as_slice().starts_with("-")).map(|c| c.clone()));
+
+                let granted = caps.iter().any(|c| c.as_slice() == "sasl");
+                if !granted
+                {
+                    link.send_raw("CAP", vec!["END"], None);
+                }
+
+                if self.sasl_requested { Some(granted) } else { None }
+            },
+            Some("NAK") =>
+            {
+                link.send_raw("CAP", vec!["END"], None);
+
+                if self.sasl_requested { Some(false) } else { None }
+            },
+            _ => None
+        }
+    }
+}
@@ -1,91 +1,465 @@
 ///////////////////////////////////////////////////////////////////////////////
 #![feature(phase)]
 extern crate circ_comms;
+extern crate getopts;
 extern crate irc;
+extern crate libc;
 #[phase(plugin, link)] extern crate log;
+extern crate openssl;
+extern crate regex;
+extern crate serialize;
 extern crate time;
 
 ///////////////////////////////////////////////////////////////////////////////
-use irc::data::config::Config;
-
+use std::io;
 use std::io::fs;
-use std::io::net::pipe::UnixListener;
+use std::io::net::pipe::{UnixAcceptor, UnixListener, UnixStream};
+use std::io::net::tcp::{TcpAcceptor, TcpListener};
 use std::io::{Listener, Acceptor};
 use std::os;
 use std::io::fs::PathExtensions;
+use std::sync::Arc;
+
+use circ_comms::Transport;
+use libc::funcs::posix88::stat_::umask;
+use libc::funcs::posix88::unistd::getuid;
+use openssl::ssl::SslContext;
 
+mod auth;
+mod capabilities;
+mod config;
 mod connection;
+mod ctcp;
+mod daemon;
+mod highlight;
+mod history;
+mod ignore;
 mod irc_channel;
+mod isupport;
+mod link;
+mod logger;
+mod networks;
+mod nick;
+
+///////////////////////////////////////////////////////////////////////////////
+struct Options
+{
+    filename: Path,
+    settings: config::Settings,
+    socket: Path,
+    // where the output goes when running as a daemon
+    daemon: Option<Path>,
+    pid_file: Option<Path>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Report a mistake in the options or configuration and stop, before
+// anything has been started
+fn exit(message: String) -> !
+{
+    let _ = io::stderr().write_line(message.as_slice());
+    unsafe { libc::exit(1) }
+}
 
 ///////////////////////////////////////////////////////////////////////////////
-fn process_args() -> Config
+fn process_args() -> Options
 {
-    match os::args().tail()
+    let opts : &[getopts::OptGroup] =
+        &[
+            getopts::optopt("", "socket", "Socket for circ to connect to (or set CIRCD_SOCKET)", "path"),
+            getopts::optflag("d", "daemon", "Detach from the terminal and run in the background"),
+            getopts::optopt("", "log", "Where a daemon's output goes (default ~/.circd/circd.log)", "path"),
+            getopts::optopt("", "pid-file", "File to write the process id to (a daemon uses circd.pid by the socket)", "path")
+        ];
+
+    let matches = match getopts::getopts(os::args().tail(), opts)
+        {
+            Ok(m) => m,
+            Err(e) => exit(format!("Invalid options\n{}", e))
+        };
+
+    let filename = match matches.free.as_slice()
     {
-        [ref arg] =>
+        [ref arg] => Path::new(arg.as_slice()),
+        _ => exit("Configuration file must be specified".to_string())
+    };
+
+    if !filename.exists()
+    {
+        exit(format!("File {} doesn't exist", filename.display()));
+    }
+
+    let mut settings = match config::load(&filename)
         {
-            let filename = Path::new(arg.as_slice());
-            
-            if !filename.exists()
-            {
-                panic!("File {} doesn't exist", arg);
-            }
+            Ok(s)  => s,
+            Err(e) => exit(e)
+        };
 
-            Config::load(filename).unwrap()
-        },
-        _ => panic!("Configuration file must be specified")
+    let socket = circ_comms::socket_path(matches.opt_str("socket"), settings.socket.take());
+
+    if matches.opt_present("log") && !matches.opt_present("daemon")
+    {
+        exit("--log is only for --daemon, otherwise the output stays on the terminal".to_string());
     }
+
+    let daemon = if matches.opt_present("daemon")
+        {
+            Some(matches.opt_str("log").map_or_else(|| daemon::default_log(), |l| Path::new(l)))
+        }
+        else
+        {
+            None
+        };
+
+    let pid_file = match matches.opt_str("pid-file")
+        {
+            Some(p) => Some(Path::new(p)),
+            None if daemon.is_some() => Some(daemon::default_pid_file(&socket)),
+            None => None
+        };
+
+    Options{filename: filename, settings: settings, socket: socket, daemon: daemon,
+            pid_file: pid_file}
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn main()
+// Make sure the socket can be created, without taking it from a circd that
+// is still running.  A socket nothing answers on is left over from a circd
+// that didn't get to clean up.  Anyone else who could get into the directory
+// could put a socket of their own in its place, so it has to be ours alone.
+fn prepare_socket(socket: &Path)
 {
-    let config = process_args();
+    let directory = socket.dir_path();
+    if !directory.exists()
+    {
+        if let Err(e) = fs::mkdir_recursive(&directory, io::USER_RWX)
+        {
+            panic!("Unable to create {}: {}", directory.display(), e);
+        }
+    }
+
+    let stat = match directory.stat()
+        {
+            Ok(s)  => s,
+            Err(e) => panic!("Unable to check {}: {}", directory.display(), e)
+        };
+
+    if stat.unstable.uid != unsafe { getuid() } as u64
+    {
+        panic!("{} belongs to another user, choose another place for the socket",
+               directory.display());
+    }
+
+    if stat.perm.intersects(io::GROUP_RWX | io::OTHER_RWX)
+    {
+        panic!("{} can be used by other users, it should only be accessible to you (chmod 700)",
+               directory.display());
+    }
 
-    let connection = connection::Connection::new(config);
-    
-    let socket = Path::new(circ_comms::address());
     if socket.exists()
     {
-        match fs::unlink(&socket)
+        if UnixStream::connect(socket).is_ok()
         {
-            Ok(_)  => (),
-            Err(e) => panic!("Unable to remove {}: {}", circ_comms::address(), e)
+            panic!("circd is already running on {}", socket.display());
+        }
+
+        if let Err(e) = fs::unlink(socket)
+        {
+            panic!("Unable to remove {}: {}", socket.display(), e);
         }
     }
+}
 
-    let stream = UnixListener::bind(&socket);
-    
-    for c in stream.listen().incoming()
+///////////////////////////////////////////////////////////////////////////////
+// How long a client has to send its request, in milliseconds
+static CLIENT_TIMEOUT: u64 = 5000;
+
+///////////////////////////////////////////////////////////////////////////////
+// Everything accepting clients, closed together by Quit or SIGTERM.  stop
+// ends the signal handling too, so nothing is left running.
+#[deriving(Clone)]
+struct Acceptors
+{
+    unix: UnixAcceptor,
+    tcp: Option<TcpAcceptor>,
+    stop: Sender<()>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Acceptors
+{
+    ///////////////////////////////////////////////////////////////////////////
+    fn close(&mut self)
+    {
+        // not a clean quit, but it works
+        let _ = self.unix.close_accept();
+
+        if let Some(ref mut t) = self.tcp
+        {
+            let _ = t.close_accept();
+        }
+
+        let _ = self.stop.send_opt(());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Serve one client on a task of its own so a slow client can't hold up
+// anyone else.  Clients from the network have to know the secret, if there
+// is one.  After a request that is answered the client may send another,
+// until it closes the connection or takes too long.  Quit stops the daemon
+// by closing the acceptors.
+fn serve(connection: networks::Networks, mut client: Transport, secret: Option<String>,
+         mut acceptors: Acceptors)
+{
+    client.set_read_timeout(Some(CLIENT_TIMEOUT));
+    client.set_write_timeout(Some(CLIENT_TIMEOUT));
+
+    if let Err(e) = circ_comms::server_hello(&mut client, secret.as_ref().map(|s| s.as_slice()))
     {
-        let mut client = match c
+        println!("Dropping client: {}", e);
+        return;
+    }
+
+    let mut first = true;
+    loop
+    {
+        let (id, request) = match circ_comms::read_request(&mut client)
+        {
+            Ok(r) => r,
+            // a client that is done with the connection just goes away
+            Err(_) if !first => return,
+            Err(e) => { println!("Dropping client: {}", e); return }
+        };
+        first = false;
+
+        let response = match request
+        {
+            circ_comms::Request::ListChannels |
+            circ_comms::Request::GetStatus(_) |
+            circ_comms::Request::GetMessages(_, _, _) |
+            circ_comms::Request::Peek(_, _, _) |
+            circ_comms::Request::SearchHistory{..} |
+            circ_comms::Request::GetUsers(_, _) |
+            circ_comms::Request::GetUsersStatus(_, _) => connection.request_response(request),
+            circ_comms::Request::Join(_, _) |
+            circ_comms::Request::Part(_, _) |
+            circ_comms::Request::ChangeNick(_) |
+            circ_comms::Request::SendMessage(_, _, _) =>
+                // nothing is sent back unless the request couldn't be routed
+                match connection.request(request)
+                {
+                    Ok(())  => return,
+                    Err(e)  => circ_comms::Response::Error(e)
+                },
+            circ_comms::Request::Subscribe(network, channels) =>
+            {
+                // events can be a long time coming
+                client.set_write_timeout(None);
+                return connection.subscribe(id, network, channels, client)
+            },
+            circ_comms::Request::Hello{..} =>
+                circ_comms::Response::Error("Hello has already been received".to_string()),
+            circ_comms::Request::Quit =>
+            {
+                connection.quit();
+                acceptors.close();
+                return
+            }
+        };
+
+        if let Err(e) = circ_comms::write_response(&mut client, id, response)
+        {
+            println!("Unable to send response: {}", e);
+            return;
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Accept remote clients until Quit closes the acceptor.  The TLS handshake
+// happens on the client's own task.
+fn accept_tcp(connection: networks::Networks,
+              mut tcp: TcpAcceptor,
+              tls: Option<Arc<SslContext>>,
+              secret: Option<String>,
+              acceptors: Acceptors)
+{
+    loop
+    {
+        let mut stream = match tcp.accept()
         {
             Ok(x) => x,
+            Err(ref e) if e.kind == io::EndOfFile => break, // closed by Quit or a signal
             Err(e) => { println!("Failed to get client: {}", e); continue }
         };
 
-        let request = match circ_comms::read_request(&mut client)
+        let networks = connection.clone();
+        let context = tls.clone();
+        let secret = secret.clone();
+        let closer = acceptors.clone();
+
+        spawn(move ||
+              {
+                  stream.set_timeout(Some(CLIENT_TIMEOUT));
+
+                  let client = match context
+                      {
+                          Some(c) => match Transport::accept_tls(stream, &*c)
+                              {
+                                  Ok(t)  => t,
+                                  Err(e) => { println!("Dropping client: {}", e); return }
+                              },
+                          None => Transport::Tcp(stream)
+                      };
+
+                  serve(networks, client, secret, closer)
+              });
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Read the configuration file again, joining channels that have been added
+// to a network and parting ones that have been removed.  The connections
+// are kept, so anything else that has changed waits for a restart.
+fn reload(networks: &networks::Networks, filename: &Path)
+{
+    match config::load(filename)
+    {
+        Ok(s) =>
+        {
+            networks.reconfigure(s);
+            println!("Reloaded {}", filename.display());
+        },
+        Err(e) => println!("Not reloading: {}", e)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn main()
+{
+    let Options{filename, mut settings, socket, daemon, pid_file} = process_args();
+
+    prepare_socket(&socket);
+
+    // only the user running circd may talk to it, from the moment the
+    // socket exists rather than once it is already listening
+    let mask = unsafe { umask(0o177) };
+    let stream = UnixListener::bind(&socket);
+    unsafe { umask(mask); }
+
+    let mut acceptor = match stream.listen()
+        {
+            Ok(a)  => a,
+            Err(e) => panic!("Unable to listen on {}: {}", socket.display(), e)
+        };
+
+    // anything that can go wrong with the listen settings is reported before
+    // a daemon loses its terminal
+    let tcp = match settings.listen.take()
         {
-            Some(r) => r,
-            None => continue
+            Some(l) =>
+            {
+                let t = match TcpListener::bind(l.address.as_slice()).listen()
+                    {
+                        Ok(a)  => a,
+                        Err(e) => panic!("Unable to listen on {}: {}", l.address, e)
+                    };
+
+                let tls = match l.tls
+                    {
+                        Some(ref s) => match s.context(true)
+                            {
+                                Ok(c)  => Some(Arc::new(c)),
+                                Err(e) => panic!("{}", e)
+                            },
+                        None =>
+                        {
+                            println!("Listening on {} without TLS", l.address);
+                            None
+                        }
+                    };
+
+                Some((t, tls, l.secret))
+            },
+            None => None
         };
-        
-        match request
-        {
-            circ_comms::Request::ListChannels => 
-                circ_comms::write_response(&mut client,
-                                           connection.request_response(request)),
-            circ_comms::Request::GetStatus =>
-                circ_comms::write_response(&mut client,
-                                           connection.request_response(request)),
-            circ_comms::Request::GetMessages(_) =>
-                circ_comms::write_response(&mut client,
-                                           connection.request_response(request)),
-            circ_comms::Request::GetUsers(_) => (),
-            circ_comms::Request::Join(_) => connection.request(request),
-            circ_comms::Request::Part(_) => connection.request(request),
-            circ_comms::Request::SendMessage(_, _) => connection.request(request),
-            circ_comms::Request::Quit => {connection.request(request); break} // not a clean quit, but it works
+
+    // no tasks may be running yet, only this one survives the fork
+    if let Some(ref log) = daemon
+    {
+        if let Err(e) = daemon::daemonize(log)
+        {
+            panic!("{}", e);
         }
     }
+
+    if let Some(ref p) = pid_file
+    {
+        if let Err(e) = daemon::write_pid(p)
+        {
+            panic!("Unable to write {}: {}", p.display(), e);
+        }
+    }
+
+    let connection = networks::Networks::new(settings);
+
+    let (stop_tx, stop_rx) = channel();
+    let acceptors = Acceptors{unix: acceptor.clone(),
+                              tcp: tcp.as_ref().map(|&(ref t, _, _)| t.clone()),
+                              stop: stop_tx};
+
+    if let Some((t, tls, secret)) = tcp
+    {
+        let networks = connection.clone();
+        let closer = acceptors.clone();
+
+        spawn(move || accept_tcp(networks, t, tls, secret, closer));
+    }
+
+    let signals = daemon::signals(stop_rx);
+    {
+        let networks = connection.clone();
+        let mut closer = acceptors.clone();
+
+        spawn(move ||
+              {
+                  for signal in signals.iter()
+                  {
+                      match signal
+                      {
+                          daemon::Signal::Reload => reload(&networks, &filename),
+                          daemon::Signal::Terminate =>
+                          {
+                              networks.quit();
+                              closer.close();
+                              break;
+                          }
+                      }
+                  }
+              });
+    }
+
+    loop
+    {
+        let client = match acceptor.accept()
+        {
+            Ok(x) => x,
+            Err(ref e) if e.kind == io::EndOfFile => break, // closed by Quit or a signal
+            Err(e) => { println!("Failed to get client: {}", e); continue }
+        };
+
+        let networks = connection.clone();
+        let closer = acceptors.clone();
+
+        // the socket is only reachable by local users, so no secret
+        spawn(move || serve(networks, Transport::Unix(client), None, closer));
+    }
+
+    let _ = fs::unlink(&socket);
+
+    if let Some(ref p) = pid_file
+    {
+        let _ = fs::unlink(p);
+    }
 }
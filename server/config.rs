@@ -0,0 +1,877 @@
+///////////////////////////////////////////////////////////////////////////////
+use irc::data::Config;
+
+use serialize::Decodable;
+use serialize::json;
+use serialize::json::Json;
+
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::io::File;
+use std::os;
+
+use time;
+
+use circ_comms::TlsSettings;
+
+use regex::Regex;
+
+use ctcp::CtcpSettings;
+use highlight::Highlights;
+use ignore::Ignores;
+use irc_channel;
+use logger::LogSettings;
+
+///////////////////////////////////////////////////////////////////////////////
+// A channel joined on connecting, with its key if it has one
+#[deriving(Clone, PartialEq)]
+pub struct AutoJoin
+{
+    pub name: String,
+    pub key: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// How to log in with SASL: PLAIN sends the account and password, EXTERNAL
+// relies on the client certificate
+#[deriving(Clone, PartialEq)]
+pub enum Mechanism
+{
+    Plain,
+    External
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Who to identify as once connected: with SASL while registering, if there
+// is a mechanism, and with NickServ afterwards if that didn't happen (and
+// nickserv is set).  The certificate is presented when connecting.  With
+// ghost NickServ is asked to disconnect whoever has taken our nick.
+#[deriving(Clone)]
+pub struct Credentials
+{
+    pub account: String,
+    pub password: Option<String>,
+    pub sasl: Option<Mechanism>,
+    pub nickserv: bool,
+    pub ghost: bool,
+    pub certificate: Option<TlsSettings>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The irc crate's settings for a network along with circd's own
+pub struct Network
+{
+    pub name: String,
+    pub config: Config,
+    // nicks to try when the configured one is taken
+    pub alt_nicks: Vec<String>,
+    pub channels: Vec<AutoJoin>,
+    pub ignore: Ignores,
+    pub history: uint,
+    pub quit_message: Option<String>,
+    pub ctcp: CtcpSettings,
+    pub credentials: Option<Credentials>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Where circd listens for remote clients, which have to know the secret or
+// have a certificate signed by the client ca
+pub struct ListenSettings
+{
+    pub address: String,
+    pub tls: Option<TlsSettings>,
+    pub secret: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub struct Settings
+{
+    pub networks: Vec<Network>,
+    pub log: Option<LogSettings>,
+    pub listen: Option<ListenSettings>,
+    pub socket: Option<Path>,
+    pub highlights: Highlights
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The keys each part of the configuration file may have, anything else is
+// most likely a typo
+static TOP_KEYS: &'static [&'static str] =
+    &["networks", "log", "listen", "socket", "highlights", "quit_message", "history", "ignore",
+      "ctcp", "secrets_file"];
+
+// what the irc crate understands
+static IRC_KEYS: &'static [&'static str] =
+    &["owners", "nickname", "username", "realname", "password", "server", "port", "use_ssl",
+      "encoding", "channels", "options"];
+
+// what a network may have besides the irc settings
+static NETWORK_KEYS: &'static [&'static str] =
+    &["ignore", "history", "quit_message", "account", "alt_nicks", "ctcp"];
+
+static LOG_KEYS: &'static [&'static str] = &["enabled", "directory", "format", "time_format"];
+static HIGHLIGHT_KEYS: &'static [&'static str] = &["keywords", "patterns"];
+static LISTEN_KEYS: &'static [&'static str] =
+    &["address", "certificate", "key", "client_ca", "secret", "secret_file", "secret_env"];
+static HISTORY_KEYS: &'static [&'static str] = &["messages"];
+static CTCP_KEYS: &'static [&'static str] = &["replies", "limit", "interval"];
+static ACCOUNT_KEYS: &'static [&'static str] =
+    &["name", "password", "password_file", "password_env", "sasl", "nickserv", "ghost", "certificate",
+      "key"];
+static CHANNEL_KEYS: &'static [&'static str] = &["name", "key"];
+
+///////////////////////////////////////////////////////////////////////////////
+fn describe(path: &[&str]) -> String
+{
+    if path.is_empty() { "the configuration".to_string() } else { path.connect(".") }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The text of the configuration file, so errors can say which line they are
+// about
+struct Source<'a>
+{
+    filename: &'a Path,
+    text: &'a str
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Where "key" is used as a key, followed by a colon, rather than appearing
+// in a string (where its quotes would be escaped) or as a value
+fn find_key(text: &str, key: &str) -> Option<uint>
+{
+    let quoted = format!("\"{}\"", key);
+    let mut from = 0;
+
+    while let Some(i) = text.slice_from(from).find_str(quoted.as_slice())
+    {
+        let start = from + i;
+        let end = start + quoted.len();
+
+        if !text.slice_to(start).ends_with("\\") &&
+           text.slice_from(end).trim_left().starts_with(":")
+        {
+            return Some(start);
+        }
+
+        from = end;
+    }
+
+    None
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl<'a> Source<'a>
+{
+    ///////////////////////////////////////////////////////////////////////////
+    // The decoded json doesn't know where anything was, so look for each key
+    // of the path after the one before it
+    fn line(&self, path: &[&str]) -> uint
+    {
+        let mut position = 0;
+
+        for key in path.iter()
+        {
+            match find_key(self.text.slice_from(position), *key)
+            {
+                Some(i) => position += i,
+                None    => break
+            }
+        }
+
+        self.text.slice_to(position).chars().filter(|&c| c == '\n').count() + 1
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn error(&self, path: &[&str], message: String) -> String
+    {
+        format!("{}:{}: {}", self.filename.display(), self.line(path), message)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn check_keys(&self, value: &Json, path: &[&str], allowed: &[&[&str]]) -> Result<(), String>
+    {
+        let object = match value.as_object()
+            {
+                Some(o) => o,
+                None    => return Err(self.error(path, format!("{} must be an object", describe(path))))
+            };
+
+        for key in object.keys()
+        {
+            if !allowed.iter().any(|a| a.contains(&key.as_slice()))
+            {
+                let mut at = path.to_vec();
+                at.push(key.as_slice());
+
+                return Err(self.error(at.as_slice(), format!("Unknown key \"{}\" in {}",
+                                                             key, describe(path))));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The object at the end of path, if there is one, checked for keys that
+    // don't belong in it
+    fn section<'b>(&self, parent: &'b Json, path: &[&str],
+                   allowed: &[&str]) -> Result<Option<&'b Json>, String>
+    {
+        match parent.find(*path.last().unwrap())
+        {
+            Some(v) => { try!(self.check_keys(v, path, &[allowed])); Ok(Some(v)) },
+            None    => Ok(None)
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn string(&self, parent: &Json, path: &[&str]) -> Result<Option<String>, String>
+    {
+        match parent.find(*path.last().unwrap())
+        {
+            Some(s) => match s.as_string()
+                {
+                    Some(s) => Ok(Some(s.to_string())),
+                    None    => Err(self.error(path, format!("\"{}\" must be a string",
+                                                            describe(path))))
+                },
+            None => Ok(None)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn decode<T: Decodable<json::Decoder, json::DecoderError>>(value: &Json,
+                                                          what: &str) -> Result<T, String>
+{
+    let mut decoder = json::Decoder::new(value.clone());
+
+    match Decodable::decode(&mut decoder)
+    {
+        Ok(v)  => Ok(v),
+        Err(e) => Err(format!("Invalid {}: {}", what, e))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A password or secret can be given in the configuration itself, in a file
+// of its own (so the configuration can be shared) or in an environment
+// variable
+fn read_secret(value: Option<String>, file: Option<String>, env: Option<String>,
+               name: &str) -> Result<Option<String>, String>
+{
+    match (value, file, env)
+    {
+        (Some(s), None, None) => Ok(Some(s)),
+        (None, Some(f), None) => match File::open(&Path::new(f.as_slice())).read_to_string()
+            {
+                Ok(s)  => Ok(Some(s.as_slice().trim().to_string())),
+                Err(e) => Err(format!("Unable to read {}: {}", f, e))
+            },
+        (None, None, Some(v)) => match os::getenv(v.as_slice())
+            {
+                Some(s) => Ok(Some(s)),
+                None    => Err(format!("The environment variable {} isn't set", v))
+            },
+        (None, None, None) => Ok(None),
+        _ => Err(format!("Only one of \"{0}\", \"{0}_file\" and \"{0}_env\" can be given", name))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct LogConfig
+{
+    enabled: Option<bool>,
+    directory: Option<String>,
+    format: Option<String>,
+    time_format: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Logging is on by default, the optional "log" object only overrides parts
+// of the defaults
+fn decode_log(value: Option<&Json>) -> Result<Option<LogSettings>, String>
+{
+    let mut settings = LogSettings::new();
+
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(Some(settings))
+        };
+
+    let log: LogConfig = try!(decode(value, "log configuration"));
+
+    if log.enabled == Some(false)
+    {
+        return Ok(None);
+    }
+
+    if let Some(d) = log.directory { settings.directory = Path::new(d); }
+    if let Some(f) = log.format { settings.format = f; }
+    if let Some(t) = log.time_format
+    {
+        // an unknown specifier would otherwise only show up when logging
+        if let Err(e) = time::now().strftime(t.as_slice())
+        {
+            return Err(format!("Invalid \"time_format\" {}: {}", t, e));
+        }
+        settings.time_format = t;
+    }
+
+    Ok(Some(settings))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct HighlightConfig
+{
+    keywords: Option<Vec<String>>,
+    patterns: Option<Vec<String>>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The current nick is always highlighted, "highlights" adds keywords and
+// regular expressions
+fn decode_highlights(value: Option<&Json>) -> Result<Highlights, String>
+{
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(Highlights::new(Vec::new(), Vec::new()))
+        };
+
+    let highlights: HighlightConfig = try!(decode(value, "highlights configuration"));
+
+    let mut patterns = Vec::new();
+    for p in highlights.patterns.unwrap_or(Vec::new()).iter()
+    {
+        match Regex::new(p.as_slice())
+        {
+            Ok(r)  => patterns.push(r),
+            Err(e) => return Err(format!("Invalid highlight pattern {}: {}", p, e))
+        }
+    }
+
+    Ok(Highlights::new(highlights.keywords.unwrap_or(Vec::new()), patterns))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct ListenConfig
+{
+    address: String,
+    certificate: Option<String>,
+    key: Option<String>,
+    client_ca: Option<String>,
+    secret: Option<String>,
+    secret_file: Option<String>,
+    secret_env: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn decode_listen(value: Option<&Json>) -> Result<Option<ListenSettings>, String>
+{
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(None)
+        };
+
+    let listen: ListenConfig = try!(decode(value, "listen configuration"));
+
+    let secret = try!(read_secret(listen.secret, listen.secret_file, listen.secret_env, "secret"));
+
+    let tls = match (listen.certificate, listen.key)
+        {
+            (Some(c), Some(k)) => Some(TlsSettings{certificate: Some(Path::new(c)),
+                                                   key: Some(Path::new(k)),
+                                                   ca: listen.client_ca.map(|c| Path::new(c))}),
+            (None, None) if listen.client_ca.is_some() =>
+                return Err("\"client_ca\" needs a \"certificate\" and \"key\"".to_string()),
+            (None, None) => None,
+            _ => return Err("\"certificate\" and \"key\" must be given together".to_string())
+        };
+
+    // anyone who can reach the port could otherwise control circd
+    if secret.is_none() && tls.as_ref().map_or(true, |t| t.ca.is_none())
+    {
+        return Err("\"listen\" needs a \"secret\" or a \"client_ca\"".to_string());
+    }
+
+    Ok(Some(ListenSettings{address: listen.address, tls: tls, secret: secret}))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct HistoryConfig
+{
+    messages: uint
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// How many messages are kept per channel for clients to read
+fn decode_history(value: Option<&Json>, default: uint) -> Result<uint, String>
+{
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(default)
+        };
+
+    let history: HistoryConfig = try!(decode(value, "history configuration"));
+
+    if history.messages == 0
+    {
+        return Err("\"messages\" must be at least 1".to_string());
+    }
+
+    Ok(history.messages)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct CtcpConfig
+{
+    replies: Option<BTreeMap<String, Option<String>>>,
+    limit: Option<uint>,
+    interval: Option<i64>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The replies to CTCP requests, where null stops a request being answered,
+// and how many may be sent in how many seconds
+fn decode_ctcp(value: Option<&Json>, default: &CtcpSettings) -> Result<CtcpSettings, String>
+{
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(default.clone())
+        };
+
+    let ctcp: CtcpConfig = try!(decode(value, "ctcp configuration"));
+    let mut settings = CtcpSettings::new();
+
+    if let Some(replies) = ctcp.replies
+    {
+        settings.replies = replies.into_iter().map(|(k, v)| (k.as_slice().to_ascii_upper(), v)).collect();
+    }
+
+    if let Some(l) = ctcp.limit { settings.limit = l; }
+
+    if let Some(i) = ctcp.interval
+    {
+        if i < 1
+        {
+            return Err("\"interval\" must be at least 1".to_string());
+        }
+        settings.interval = i;
+    }
+
+    Ok(settings)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn decode_ignore(value: Option<&Json>) -> Result<Vec<String>, String>
+{
+    match value
+    {
+        Some(v) => decode(v, "ignore list, it must be a list of nick!user@host masks"),
+        None    => Ok(Vec::new())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Decodable)]
+struct AccountConfig
+{
+    name: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    password_env: Option<String>,
+    sasl: Option<String>,
+    nickserv: Option<bool>,
+    ghost: Option<bool>,
+    certificate: Option<String>,
+    key: Option<String>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The account name defaults to the nick.  SASL PLAIN is used unless "sasl"
+// says "external" or "none", with NickServ as the fallback unless "nickserv"
+// is false.  Ghosting is only done when asked for.  A certificate can only be
+// presented over TLS.
+fn decode_account(value: Option<&Json>, nickname: &str,
+                  use_ssl: bool) -> Result<Option<Credentials>, String>
+{
+    let value = match value
+        {
+            Some(v) => v,
+            None    => return Ok(None)
+        };
+
+    let account: AccountConfig = try!(decode(value, "account configuration"));
+
+    let password = try!(read_secret(account.password, account.password_file, account.password_env,
+                                    "password"));
+
+    let sasl = match account.sasl.as_ref().map(|s| s.as_slice())
+        {
+            Some("plain") | None => Some(Mechanism::Plain),
+            Some("external")     => Some(Mechanism::External),
+            Some("none")         => None,
+            Some(s) => return Err(format!("Unknown \"sasl\" mechanism {}, use plain, external or none", s))
+        };
+
+    let certificate = match (account.certificate, account.key)
+        {
+            (Some(c), Some(k)) => Some(TlsSettings{certificate: Some(Path::new(c)),
+                                                   key: Some(Path::new(k)),
+                                                   ca: None}),
+            (None, None) => None,
+            _ => return Err("\"certificate\" and \"key\" must be given together".to_string())
+        };
+
+    if certificate.is_some() && !use_ssl
+    {
+        return Err("A \"certificate\" needs \"use_ssl\" for the network".to_string());
+    }
+
+    // NickServ needs a password
+    let nickserv = account.nickserv.unwrap_or(true) && password.is_some();
+
+    if sasl == Some(Mechanism::External) && certificate.is_none()
+    {
+        return Err("SASL EXTERNAL needs a \"certificate\" and \"key\"".to_string());
+    }
+
+    if password.is_none() && (sasl == Some(Mechanism::Plain) || sasl.is_none())
+    {
+        return Err("\"account\" needs a \"password\"".to_string());
+    }
+
+    if sasl.is_none() && !nickserv
+    {
+        return Err("\"account\" needs \"sasl\" or \"nickserv\"".to_string());
+    }
+
+    let ghost = account.ghost.unwrap_or(false);
+    if ghost && password.is_none()
+    {
+        return Err("\"ghost\" needs a \"password\"".to_string());
+    }
+
+    Ok(Some(Credentials{account: account.name.unwrap_or(nickname.to_string()),
+                        password: password, sasl: sasl, nickserv: nickserv, ghost: ghost,
+                        certificate: certificate}))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Each channel is either a name or an object with a "name" and a "key"
+fn decode_channels(source: &Source, value: Option<&Json>,
+                   path: &[&str]) -> Result<Vec<AutoJoin>, String>
+{
+    let list = match value
+        {
+            Some(v) => match v.as_array()
+                {
+                    Some(l) => l,
+                    None    => return Err(source.error(path, "\"channels\" must be a list".to_string()))
+                },
+            None => return Ok(Vec::new())
+        };
+
+    let mut channels = Vec::new();
+
+    for c in list.iter()
+    {
+        let channel = match c.as_string()
+            {
+                Some(name) => AutoJoin{name: name.to_string(), key: None},
+                None =>
+                {
+                    try!(source.check_keys(c, path, &[CHANNEL_KEYS]));
+                    match (c.find("name").and_then(|n| n.as_string()),
+                           c.find("key").and_then(|k| k.as_string()))
+                    {
+                        (Some(n), k) => AutoJoin{name: n.to_string(), key: k.map(|k| k.to_string())},
+                        (None, _) => return Err(source.error(path, "A channel needs a \"name\"".to_string()))
+                    }
+                }
+            };
+
+        channels.push(channel);
+    }
+
+    Ok(channels)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What a network gets unless it has settings of its own.  Ignore lists are
+// combined rather than replaced.
+struct Defaults
+{
+    ignore: Vec<String>,
+    history: uint,
+    quit_message: Option<String>,
+    ctcp: CtcpSettings
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn decode_network(source: &Source, value: &Json, path: &[&str],
+                  defaults: &Defaults) -> Result<Network, String>
+{
+    let at = |key: &'static str| { let mut p = path.to_vec(); p.push(key); p };
+
+    let channels = try!(decode_channels(source, value.find("channels"), at("channels").as_slice()));
+
+    // circd joins the channels itself, so they can be parted and joined
+    // again later; the irc crate would join them on every connection
+    let mut irc = BTreeMap::new();
+    for (k, v) in value.as_object().unwrap().iter()
+    {
+        if IRC_KEYS.contains(&k.as_slice()) && k.as_slice() != "channels"
+        {
+            irc.insert(k.clone(), v.clone());
+        }
+    }
+    irc.insert("channels".to_string(), Json::Array(Vec::new()));
+
+    let config: Config = try!(decode(&Json::Object(irc), "irc configuration")
+                              .map_err(|e| source.error(path, e)));
+
+    let alt_nicks: Vec<String> = match value.find("alt_nicks")
+        {
+            Some(v) => try!(decode(v, "alt_nicks, it must be a list of nicks")
+                            .map_err(|e| source.error(at("alt_nicks").as_slice(), e))),
+            None    => Vec::new()
+        };
+
+    let mut ignore = defaults.ignore.clone();
+    ignore.extend(try!(decode_ignore(value.find("ignore"))
+                       .map_err(|e| source.error(at("ignore").as_slice(), e))).into_iter());
+
+    let history = try!(decode_history(try!(source.section(value, at("history").as_slice(), HISTORY_KEYS)),
+                                      defaults.history)
+                       .map_err(|e| source.error(at("history").as_slice(), e)));
+
+    let quit_message = match try!(source.string(value, at("quit_message").as_slice()))
+        {
+            Some(m) => Some(m),
+            None    => defaults.quit_message.clone()
+        };
+
+    let ctcp = try!(decode_ctcp(try!(source.section(value, at("ctcp").as_slice(), CTCP_KEYS)), &defaults.ctcp)
+                    .map_err(|e| source.error(at("ctcp").as_slice(), e)));
+
+    let credentials = try!(decode_account(try!(source.section(value, at("account").as_slice(), ACCOUNT_KEYS)),
+                                          config.nickname.as_slice(), config.use_ssl)
+                           .map_err(|e| source.error(at("account").as_slice(), e)));
+
+    Ok(Network{name: config.server.clone(), config: config, alt_nicks: alt_nicks, channels: channels,
+               ignore: Ignores::new(ignore), history: history,
+               quit_message: quit_message, ctcp: ctcp, credentials: credentials})
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn read_json(filename: &Path) -> Result<(String, Json), String>
+{
+    let contents = match File::open(filename).read_to_string()
+        {
+            Ok(s)  => s,
+            Err(e) => return Err(format!("Unable to read {}: {}", filename.display(), e))
+        };
+
+    match json::from_str(contents.as_slice())
+    {
+        Ok(j)  => Ok((contents, j)),
+        Err(json::ParserError::SyntaxError(code, line, column)) =>
+            Err(format!("{}:{}:{}: {}", filename.display(), line, column, json::error_str(code))),
+        Err(e) => Err(format!("Unable to parse {}: {}", filename.display(), e))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The "secrets_file" has the "account" of each network, by network name, so
+// passwords can be kept out of a configuration that is shared.  An account
+// in the configuration itself wins.
+fn load_secrets(filename: &Path, networks: &mut Vec<Network>) -> Result<(), String>
+{
+    let (contents, json) = try!(read_json(filename));
+    let source = Source{filename: filename, text: contents.as_slice()};
+
+    {
+        let names: Vec<&str> = networks.iter().map(|n| n.name.as_slice()).collect();
+        try!(source.check_keys(&json, &[], &[names.as_slice()]));
+    }
+
+    for n in networks.iter_mut().filter(|n| n.credentials.is_none())
+    {
+        let path = [n.name.as_slice()];
+
+        n.credentials = try!(decode_account(try!(source.section(&json, &path, ACCOUNT_KEYS)),
+                                            n.config.nickname.as_slice(), n.config.use_ssl)
+                             .map_err(|e| source.error(&path, e)));
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The configuration file is either the settings for a single network (the
+// original format) or an object of the form { "networks": { "name": { ... } } }.
+// Either form may also contain a "log" object, a "listen" object to accept
+// clients over the network, the "socket" path for local clients, a
+// "highlights" object, "ignore", "history", "quit_message" and "ctcp"
+// settings for every network and a "secrets_file" with their accounts.  Unknown keys are
+// errors, reported with their line.
+pub fn load(filename: &Path) -> Result<Settings, String>
+{
+    let (contents, json) = try!(read_json(filename));
+
+    let source = Source{filename: filename, text: contents.as_slice()};
+    let mut networks = Vec::new();
+
+    match json.find("networks")
+    {
+        Some(n) =>
+        {
+            try!(source.check_keys(&json, &[], &[TOP_KEYS]));
+
+            let object = match n.as_object()
+                {
+                    Some(o) => o,
+                    None    => return Err(source.error(&["networks"],
+                                                       "\"networks\" must be an object".to_string()))
+                };
+
+            let defaults = Defaults{
+                ignore: try!(decode_ignore(json.find("ignore"))
+                             .map_err(|e| source.error(&["ignore"], e))),
+                history: try!(decode_history(try!(source.section(&json, &["history"], HISTORY_KEYS)),
+                                             irc_channel::HISTORY_LIMIT)
+                              .map_err(|e| source.error(&["history"], e))),
+                quit_message: try!(source.string(&json, &["quit_message"])),
+                ctcp: try!(decode_ctcp(try!(source.section(&json, &["ctcp"], CTCP_KEYS)), &CtcpSettings::new())
+                           .map_err(|e| source.error(&["ctcp"], e)))};
+
+            for (name, value) in object.iter()
+            {
+                let path = ["networks", name.as_slice()];
+
+                try!(source.check_keys(value, &path, &[IRC_KEYS, NETWORK_KEYS]));
+
+                let mut network = try!(decode_network(&source, value, &path, &defaults));
+                network.name = name.clone();
+                networks.push(network);
+            }
+        },
+        None =>
+        {
+            try!(source.check_keys(&json, &[], &[TOP_KEYS, IRC_KEYS, NETWORK_KEYS]));
+
+            // the network's own settings are the top level ones
+            let defaults = Defaults{ignore: Vec::new(), history: irc_channel::HISTORY_LIMIT,
+                                    quit_message: None, ctcp: CtcpSettings::new()};
+
+            let network = try!(decode_network(&source, &json, &[], &defaults));
+            networks.push(network);
+        }
+    }
+
+    if networks.is_empty()
+    {
+        return Err(format!("No networks configured in {}", filename.display()));
+    }
+
+    if let Some(f) = try!(source.string(&json, &["secrets_file"]))
+    {
+        try!(load_secrets(&Path::new(f), &mut networks));
+    }
+
+    let log = try!(decode_log(try!(source.section(&json, &["log"], LOG_KEYS)))
+                   .map_err(|e| source.error(&["log"], e)));
+
+    let listen = try!(decode_listen(try!(source.section(&json, &["listen"], LISTEN_KEYS)))
+                      .map_err(|e| source.error(&["listen"], e)));
+
+    let socket = try!(source.string(&json, &["socket"])).map(|s| Path::new(s));
+
+    let highlights = try!(decode_highlights(try!(source.section(&json, &["highlights"], HIGHLIGHT_KEYS)))
+                          .map_err(|e| source.error(&["highlights"], e)));
+
+    Ok(Settings{networks: networks, log: log, listen: listen, socket: socket,
+                highlights: highlights})
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{IRC_KEYS, NETWORK_KEYS, TOP_KEYS, Source, find_key};
+
+    use serialize::json;
+
+    static TEXT: &'static str = r#"{
+    "quit_message": "the \"server\": is leaving",
+    "history": 5,
+    "networks": {
+        "libera": {
+            "server": "irc.libera.chat",
+            "nick": "me"
+        }
+    },
+    "colour": true
+}"#;
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn finds_keys_rather_than_values_or_strings()
+    {
+        assert_eq!(find_key(TEXT, "quit_message"), Some(6));
+        // inside the quit message, with its quotes escaped, then as a key
+        assert_eq!(find_key(TEXT, "server"), TEXT.find_str("\"server\": \"irc"));
+        assert_eq!(find_key(TEXT, "libera"), TEXT.find_str("\"libera\""));
+        assert_eq!(find_key("{\"a\": \"history\"}", "history"), None);
+        assert_eq!(find_key(TEXT, "missing"), None);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn reports_a_bad_key_with_its_line()
+    {
+        let filename = Path::new("circd.json");
+        let source = Source{filename: &filename, text: TEXT};
+        let json = json::from_str(TEXT).unwrap();
+        let network = json.find("networks").and_then(|n| n.find("libera")).unwrap();
+
+        assert_eq!(source.check_keys(network, &["networks", "libera"], &[IRC_KEYS, NETWORK_KEYS]),
+                   Err("circd.json:7: Unknown key \"nick\" in networks.libera".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn reports_an_unknown_top_level_key()
+    {
+        let filename = Path::new("circd.json");
+        let source = Source{filename: &filename, text: TEXT};
+        let json = json::from_str(TEXT).unwrap();
+
+        assert_eq!(source.check_keys(&json, &[], &[TOP_KEYS]),
+                   Err("circd.json:10: Unknown key \"colour\" in the configuration".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn reports_a_value_of_the_wrong_type_with_its_line()
+    {
+        let filename = Path::new("circd.json");
+        let source = Source{filename: &filename, text: TEXT};
+        let json = json::from_str(TEXT).unwrap();
+
+        assert_eq!(source.string(&json, &["history"]),
+                   Err("circd.json:3: \"history\" must be a string".to_string()));
+    }
+}
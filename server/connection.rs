@@ -1,130 +1,552 @@
 ///////////////////////////////////////////////////////////////////////////////
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::{Occupied,Vacant};
+use std::io::Timer;
 use std::string::String;
-use std::sync::Arc;
+use std::time::Duration;
 
 use time;
 use time::Timespec;
 
+use auth::Auth;
+use capabilities::Capabilities;
 use circ_comms;
 use circ_comms::{Request, Response};
+use config;
+use config::AutoJoin;
+use ctcp;
+use ctcp::Ctcp;
+use highlight::Highlights;
+use history;
+use ignore::Ignores;
 use irc_channel;
+use isupport::{ISupport, ModeKind, same_nick};
+use link::{Incoming, Link, Tags};
+use logger;
+use logger::{ChannelLog, LogSettings};
+use nick::Nicks;
 
-use irc::data::{Config, Message};
+use irc::data::Message;
 use irc::data::Command::{JOIN, PART, PRIVMSG, QUIT};
-use irc::server::{IrcServer, Server, NetIrcServer};
-use irc::server::utils::Wrapper;
 
+use regex::Regex;
 
 
 
+
+///////////////////////////////////////////////////////////////////////////////
+// A request for the process task along with where to send the response, if
+// one is wanted.  Each request has its own reply channel so clients being
+// served at the same time can't get each other's responses.
+type Job = (Request, Option<Sender<Response>>);
+
 ///////////////////////////////////////////////////////////////////////////////
+#[deriving(Clone)]
 pub struct Connection
 {
-    process_tx: Sender<Request>,
-    process_rx: Receiver<Response>
+    pub network: String,
+    process_tx: Sender<Job>,
+    subscribe_tx: Sender<Subscriber>,
+    configure_tx: Sender<Vec<AutoJoin>>
 }
 
+///////////////////////////////////////////////////////////////////////////////
+// A client following channels, events are sent as Response::Event until the
+// receiving end goes away.  No channels means every channel.
+pub struct Subscriber
+{
+    pub channels: Vec<String>,
+    pub tx: Sender<Response>
+}
 
 ///////////////////////////////////////////////////////////////////////////////
-fn rx_task(server: Arc<NetIrcServer>,
-           tx: Sender<(Timespec, Message)>)
+impl Subscriber
 {
-    spawn(move ||
-          {
-              for message in server.iter()
-              {
-                  debug!("{}", message.into_string());
-                  tx.send((time::get_time(), message));
-              }
-          });
+    fn wants(&self, channel: &str) -> bool
+    {
+        self.channels.is_empty() || self.channels.iter().any(|c| c.as_slice() == channel)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// Everything the process task knows about one network
+struct State
+{
+    network: String,
+    nicks: Nicks,
+    log: Option<LogSettings>,
+    highlights: Highlights,
+    ignore: Ignores,
+    auth: Auth,
+    caps: Capabilities,
+    ctcp: Ctcp,
+    isupport: ISupport,
+    history: uint,
+    quit_message: Option<String>,
+    channels: HashMap<String, irc_channel::Channel>,
+    // the channels from the configuration file, and the keys of any that
+    // have one
+    configured: Vec<AutoJoin>,
+    keys: HashMap<String, String>,
+    joined: HashSet<String>,
+    rejoin: bool,
+    subscribers: Vec<Subscriber>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl State
+{
+    ///////////////////////////////////////////////////////////////////////////
+    // The configured channels are joined once registration has finished,
+    // like any others being rejoined
+    fn new(network: &config::Network, log: Option<LogSettings>, highlights: Highlights) -> State
+    {
+        State{network: network.name.clone(), nicks: Nicks::new(network), log: log,
+              highlights: highlights, ignore: network.ignore.clone(),
+              auth: Auth::new(network.credentials.clone()), caps: Capabilities::new(),
+              ctcp: Ctcp::new(network.ctcp.clone()), isupport: ISupport::new(),
+              history: network.history,
+              quit_message: network.quit_message.clone(), channels: HashMap::new(),
+              configured: network.channels.clone(),
+              keys: network.channels.iter()
+                                    .filter_map(|c| c.key.as_ref().map(|k| (c.name.clone(), k.clone())))
+                                    .collect(),
+              joined: network.channels.iter().map(|c| c.name.clone()).collect(), rejoin: true,
+              subscribers: Vec::new()}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Pass an event on to everyone following the channel, forgetting
+    // subscribers that have gone away
+    fn publish(&mut self, event: circ_comms::Event)
+    {
+        let network = self.network.clone();
+
+        self.subscribers.retain(|s| !s.wants(event.channel()) ||
+                                    s.tx.send_opt(Response::Event(network.clone(),
+                                                                  event.clone())).is_ok());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Get a channel, creating it (and its log) the first time it is seen
+    fn channel(&mut self, name: &str) -> &mut irc_channel::Channel
+    {
+        match self.channels.entry(name.to_string())
+        {
+            Vacant(entry) =>
+            {
+                let log = match self.log
+                    {
+                        Some(ref s) => Some(ChannelLog::new(s, self.network.as_slice(), name)),
+                        None        => None
+                    };
+                entry.set(irc_channel::Channel::new(name, log, self.history))
+            },
+            Occupied(entry) => entry.into_mut()
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn set_topic(channels: &mut HashMap<String, irc_channel::Channel>, msg: Message)
+fn set_topic(state: &mut State, msg: (Timespec, Message))
 {
-    let name = msg.args[0].clone();
-    let topic = match msg.suffix
+    let name = msg.1.args[0].clone();
+    let topic = match msg.1.suffix
        {
-           Some(t) => t,
-           None    => "No topic provided".to_string()
+           Some(ref t) => t.clone(),
+           None        => "No topic provided".to_string()
        };
 
-    match channels.entry(name.clone())
     {
-        Vacant(entry) =>
+        let channel = state.channel(name.as_slice());
+        channel.set_topic(topic.as_slice());
+        channel.log_event(&msg);
+    }
+
+    state.publish(circ_comms::Event::Topic(name, nick_of(&msg.1), topic, msg.0));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn nick_of(msg: &Message) -> String
+{
+    match msg.prefix
+    {
+        Some(ref p) => p.as_slice().split('!').next().unwrap().to_string(),
+        None        => String::new()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// JOIN, PART and NICK have their parameter either as the first argument or,
+// with some servers, as the suffix
+fn first_param(msg: &Message) -> Option<String>
+{
+    match msg.args.as_slice().first()
+    {
+        Some(a) => Some(a.clone()),
+        None    => msg.suffix.clone()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn join(state: &mut State, msg: (Timespec, Message))
+{
+    let name = match first_param(&msg.1) { Some(n) => n, None => return };
+    let user = match msg.1.prefix
         {
-            let mut c = irc_channel::Channel::new(name.as_slice());
-            c.set_topic(topic.as_slice());
-            entry.set(c);
-        },
-        Occupied(mut entry) => entry.get_mut().set_topic(topic.as_slice())
-    };
+            Some(ref p) => irc_channel::User::parse(p.as_slice(), state.isupport.prefixes()),
+            None        => return
+        };
+
+    if same_nick(user.name.as_slice(), state.nicks.current())
+    {
+        state.joined.insert(name.clone());
+    }
+
+    let nick = user.name.clone();
+    {
+        let channel = state.channel(name.as_slice());
+        channel.add_user(user);
+        channel.log_event(&msg);
+    }
+
+    state.publish(circ_comms::Event::Join(name, nick, msg.0));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Someone leaving a channel, either by PART or by being kicked.  When it's
+// us the member list is no longer being kept up to date.
+fn leave(state: &mut State, name: &str, nick: &str, msg: &(Timespec, Message))
+{
+    let ours = same_nick(nick, state.nicks.current());
+    if ours
+    {
+        state.joined.remove(&name.to_string());
+    }
+
+    {
+        let channel = state.channel(name);
+
+        if ours
+        {
+            channel.users.clear();
+        }
+        else
+        {
+            channel.remove_user(nick);
+        }
+        channel.log_event(msg);
+    }
+
+    state.publish(circ_comms::Event::Part(name.to_string(), nick.to_string(), msg.0));
 }
+
 ///////////////////////////////////////////////////////////////////////////////
-fn add_message(channels: &mut HashMap<String, irc_channel::Channel>,
-               msg: (Timespec, Message))
+fn part(state: &mut State, msg: (Timespec, Message))
 {
+    let name = match first_param(&msg.1) { Some(n) => n, None => return };
+    let nick = nick_of(&msg.1);
+
+    leave(state, name.as_slice(), nick.as_slice(), &msg);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn kick(state: &mut State, msg: (Timespec, Message))
+{
+    if msg.1.args.len() < 2 { return; }
+
     let name = msg.1.args[0].clone();
-    if name == "AUTH".to_string() { return (); }
+    let nick = msg.1.args[1].clone();
+
+    leave(state, name.as_slice(), nick.as_slice(), &msg);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn quit(state: &mut State, msg: (Timespec, Message))
+{
+    let nick = nick_of(&msg.1);
+    let mut left = Vec::new();
 
-    match channels.entry(name.clone())
+    for channel in state.channels.values_mut()
     {
-        Vacant(entry) =>
+        if channel.remove_user(nick.as_slice())
         {
-            let mut c = irc_channel::Channel::new(name.as_slice());
-            c.add(msg.clone());
-            entry.set(c);
-        },
-        Occupied(mut entry) => entry.get_mut().add(msg.clone())
-    };
+            channel.log_event(&msg);
+            left.push(channel.name.clone());
+        }
+    }
+
+    for name in left.into_iter()
+    {
+        state.publish(circ_comms::Event::Quit(name, nick.clone(), msg.0));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn change_nick(state: &mut State, msg: (Timespec, Message))
+{
+    let old = nick_of(&msg.1);
+    let new = match first_param(&msg.1) { Some(n) => n, None => return };
+
+    state.nicks.renamed(old.as_slice(), new.as_slice());
+
+    let mut renamed = Vec::new();
+
+    for channel in state.channels.values_mut()
+    {
+        // a query with the user keeps its name, but shows the change
+        if channel.rename_user(old.as_slice(), new.as_slice()) ||
+           same_nick(channel.name.as_slice(), old.as_slice())
+        {
+            channel.log_event(&msg);
+            renamed.push(channel.name.clone());
+        }
+    }
+
+    for name in renamed.into_iter()
+    {
+        state.publish(circ_comms::Event::Nick(name, old.clone(), new.clone(), msg.0));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// RPL_NAMREPLY: <me> <type> <channel> :<names>
+fn names_reply(state: &mut State, msg: (Timespec, Message))
+{
+    if msg.1.args.len() < 3 { return; }
+
+    let names = match msg.1.suffix { Some(ref s) => s.clone(), None => return };
+
+    let prefixes = state.isupport.prefixes().to_string();
+    state.channel(msg.1.args[2].as_slice()).add_names(names.as_slice(), prefixes.as_slice());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// RPL_ENDOFNAMES: <me> <channel> :End of /NAMES list
+fn end_names(state: &mut State, msg: (Timespec, Message))
+{
+    if msg.1.args.len() < 2 { return; }
+
+    state.channel(msg.1.args[1].as_slice()).end_names();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Track the membership modes of a channel MODE: <channel> <modes> [params]
+fn channel_mode(state: &mut State, msg: (Timespec, Message))
+{
+    if msg.1.args.len() < 2 { return; }
+
+    let name = msg.1.args[0].clone();
+    if !name.as_slice().starts_with("#") && !name.as_slice().starts_with("&")
+    {
+        return;
+    }
+
+    let mut params = msg.1.args.iter().skip(2);
+    let mut add = true;
+    let mut changes = Vec::new();
+
+    // which modes take a parameter comes from the server's ISUPPORT
+    for c in msg.1.args[1].as_slice().chars()
+    {
+        match c
+        {
+            '+' => add = true,
+            '-' => add = false,
+            c   => match state.isupport.mode_kind(c, add)
+                {
+                    ModeKind::Prefix(prefix) =>
+                        if let Some(nick) = params.next()
+                        {
+                            changes.push((nick.clone(), prefix, add));
+                        },
+                    ModeKind::Param   => { params.next(); },
+                    ModeKind::NoParam => ()
+                }
+        }
+    }
+
+    let prefixes = state.isupport.prefixes().to_string();
+    let channel = state.channel(name.as_slice());
+
+    for &(ref nick, prefix, add) in changes.iter()
+    {
+        channel.set_user_mode(nick.as_slice(), prefix, add, prefixes.as_slice());
+    }
+
+    channel.log_event(&msg);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Messages sent to us by a user are filed under their nick, as a query.  A
+// private message always counts as a mention, what we sent ourselves and CTCP
+// other than actions never do.
+fn add_message(state: &mut State, msg: (Timespec, Message), tags: Tags)
+{
+    let target = match msg.1.args.get(0) { Some(t) => t.clone(), None => return };
+    if target == "AUTH".to_string() { return (); }
+
+    if msg.1.prefix.as_ref().map_or(false, |p| state.ignore.matches(p.as_slice())) { return; }
+
+    let from_user = msg.1.prefix.as_ref().map_or(false, |p| p.as_slice().contains_char('!'));
+    let query = from_user && same_nick(target.as_slice(), state.nicks.current());
+
+    let name = if query { nick_of(&msg.1) } else { target };
+    let ours = same_nick(nick_of(&msg.1).as_slice(), state.nicks.current());
+
+    let mention = !ours && match msg.1.suffix
+        {
+            Some(ref text) => match ctcp::parse(text.as_slice())
+                {
+                    Some((ref command, ref action)) if command.as_slice() == "ACTION" =>
+                        query || state.highlights.matches(state.nicks.current(), action.as_slice()),
+                    Some(_) => false,
+                    None    => query || state.highlights.matches(state.nicks.current(), text.as_slice())
+                },
+            None => query
+        };
+
+    let message = state.channel(name.as_slice()).add(msg, tags, mention);
+    if ours
+    {
+        state.channel(name.as_slice()).read_own();
+    }
+
+    state.publish(circ_comms::Event::Message(name, message));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A CTCP request from another user, which gets an automatic reply.  Actions
+// are only messages.
+fn ctcp_request(state: &mut State, link: &Link, msg: &Message)
+{
+    if !msg.prefix.as_ref().map_or(false, |p| p.as_slice().contains_char('!')) { return; }
+    if msg.prefix.as_ref().map_or(false, |p| state.ignore.matches(p.as_slice())) { return; }
+
+    let (command, params) = match msg.suffix.as_ref().and_then(|s| ctcp::parse(s.as_slice()))
+        {
+            Some(c) => c,
+            None    => return
+        };
+
+    let nick = nick_of(msg);
+    if command.as_slice() != "ACTION" && !same_nick(nick.as_slice(), state.nicks.current())
+    {
+        state.ctcp.request(link, nick.as_slice(), command.as_slice(), params.as_slice());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Without echo-message the server never tells us about what we sent, so it
+// goes into the history as it is sent
+fn send_message(state: &mut State, link: &Link, channel: String, text: String)
+{
+    if !link.send(PRIVMSG(channel.as_slice(), text.as_slice()))
+    {
+        println!("Message to {} not sent while disconnected", channel);
+        return;
+    }
+
+    if !state.caps.has("echo-message")
+    {
+        let msg = Message::new(Some(state.nicks.current()), "PRIVMSG",
+                               Some(vec![channel.as_slice()]), Some(text.as_slice()));
+        add_message(state, (time::get_time(), msg), Tags::new());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// A nick we asked for wasn't given to us.  When clients need to know, it is
+// filed as a notice from the server, as the server's other notices are.
+fn nick_refused(state: &mut State, link: &Link, time: Timespec, msg: &Message)
+{
+    let report = match state.nicks.in_use(link, msg, state.isupport.nicklen())
+        {
+            Some(r) => r,
+            None    => return
+        };
+
+    let notice = Message::new(msg.prefix.as_ref().map(|p| p.as_slice()), "NOTICE",
+                              Some(vec![state.nicks.current()]), Some(report.as_slice()));
+    add_message(state, (time, notice), Tags::new());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// away-notify: AWAY :<message> when going away, AWAY alone when back
+fn set_away(state: &mut State, msg: &Message)
+{
+    let nick = nick_of(msg);
+
+    for channel in state.channels.values_mut()
+    {
+        channel.set_away(nick.as_slice(), msg.suffix.clone());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// account-notify: ACCOUNT <account>, or ACCOUNT * when logged out
+fn set_account(state: &mut State, msg: &Message)
+{
+    let nick = nick_of(msg);
+    let account = match first_param(msg)
+        {
+            Some(ref a) if a.as_slice() == "*" => None,
+            a => a
+        };
+
+    for channel in state.channels.values_mut()
+    {
+        channel.set_account(nick.as_slice(), account.clone());
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The server's capabilities, which decide whether SASL can be used
+fn capability(state: &mut State, link: &Link, msg: &Message)
+{
+    let sasl = state.auth.wants_sasl();
+
+    match state.caps.handle(link, msg, sasl)
+    {
+        Some(true)  => state.auth.start(link),
+        Some(false) => state.auth.unavailable(),
+        None        => ()
+    }
 }
  
 ///////////////////////////////////////////////////////////////////////////////
-fn get_channels(channels: &HashMap<String, irc_channel::Channel>) -> Response
+fn get_channels(state: &State) -> Response
 {
     let mut names : Vec<String> = Vec::new();
 
-    for name in channels.keys()
+    for name in state.channels.keys()
     {
         names.push(name.clone());
     }
 
-    circ_comms::Response::Channels(names)
+    circ_comms::Response::Channels(vec![(state.network.clone(), names)])
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn get_messages(channels: &mut HashMap<String, irc_channel::Channel>,
-                name: &str) -> Response
+// Unread messages for the reader, which are then marked as read unless the
+// reader is only peeking
+fn get_messages(state: &mut State, name: &str, reader: &str, mark_read: bool) -> Response
 {
-    let channel = channels.get_mut(&name.to_string());
+    let channel = state.channels.get_mut(&name.to_string());
 
     match channel
     {
         Some(c) =>
         {
-            let mut r = Vec::new();
-            for m in c.messages.iter()
+            let r: Vec<circ_comms::Message> = c.unread_msgs(reader).iter()
+                .map(|m| (*m).clone())
+                .collect();
+            if mark_read
             {
-                // TODO: I need to understand the borrowing much better...
-                let user = match m.1.prefix.clone()
-                    {
-                        Some(p) => p,
-                        None    => "Unknown User".to_string()
-                    };
-                let msg = match m.1.suffix.clone()
-                    {
-                        Some(m) => m,
-                        None    => "No message".to_string()
-                    };
-
-                r.push(circ_comms::Message::new(m.0,
-                                                user.as_slice(),
-                                                msg.as_slice()));
+                c.mark_read(reader);
             }
-            c.clear();
-            circ_comms::Response::Messages(r)
+            circ_comms::Response::Messages(state.network.clone(), r)
         },
         None    => circ_comms::Response::Error(format!("Unknown channel {}", name))
     }
@@ -132,61 +554,310 @@ fn get_messages(channels: &mut HashMap<String, irc_channel::Channel>,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-fn get_status(channels: &HashMap<String, irc_channel::Channel>) -> Response
+// The nicks alone, or with what is known about each user
+fn get_users(state: &State, name: &str, with_status: bool) -> Response
 {
-    let mut statuses: Vec<(String, uint)> = Vec::new();
+    let users = match state.channels.get(&name.to_string())
+        {
+            Some(c) => c.user_list(state.isupport.prefixes()),
+            None    => return circ_comms::Response::Error(format!("Unknown channel {}", name))
+        };
 
-    for (name, channel) in channels.iter()
+    if with_status
+    {
+        circ_comms::Response::UsersStatus(state.network.clone(), users)
+    }
+    else
     {
-        statuses.push((name.to_string(), channel.messages.len()));
+        circ_comms::Response::Users(state.network.clone(), users.into_iter().map(|u| u.name).collect())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Reading the history can take a while, so it is searched on a task of its
+// own which answers the client itself
+fn search_history(state: &State, reply: Option<Sender<Response>>, channel: String, pattern: &str,
+                  since: Option<Timespec>, until: Option<Timespec>,
+                  user: Option<String>, limit: Option<uint>)
+{
+    let settings = match state.log
+        {
+            Some(ref s) => s,
+            None        => return respond(&reply, circ_comms::Response::Error(
+                               "History isn't kept when logging is disabled".to_string()))
+        };
+
+    let regex = match Regex::new(pattern)
+        {
+            Ok(r)  => r,
+            Err(e) => return respond(&reply, circ_comms::Response::Error(
+                               format!("Invalid pattern {}: {}", pattern, e)))
+        };
+
+    let query = history::Query{pattern: regex, since: since, until: until,
+                               user: user, limit: limit};
+    let directory = logger::directory(settings, state.network.as_slice(), channel.as_slice());
+    let network = state.network.clone();
+
+    spawn(move ||
+          {
+              let response = match history::search(&directory, &query)
+                  {
+                      Ok(m)  => circ_comms::Response::Messages(network, m),
+                      Err(e) => circ_comms::Response::Error(
+                                    format!("Unable to search history of {}: {}", channel, e))
+                  };
+
+              respond(&reply, response);
+          });
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn get_status(state: &State, link: &Link, reader: &str) -> Response
+{
+    let mut status = circ_comms::NetworkStatus::new(state.network.as_slice(), link.state(),
+                                                    state.auth.state());
+
+    for (name, channel) in state.channels.iter()
+    {
+        status.channels.push(circ_comms::ChannelStatus{name: name.to_string(),
+                                                       unread: channel.unread_count(reader),
+                                                       mentions: channel.unread_mentions(reader)});
     }
     
-    circ_comms::Response::Status(statuses)
+    circ_comms::Response::Status(vec![status])
     
 }
 ///////////////////////////////////////////////////////////////////////////////
-fn process_task(rx: Receiver<(Timespec, Message)>,
-                tx: Arc<NetIrcServer>,
-                response_tx: Sender<Response>, 
-                request_rx: Receiver<Request>)
+// A new connection has been made.  Member lists are out of date until the
+// server sends them again and the channels we were in need joining once
+// registration has finished.
+fn reconnected(state: &mut State)
+{
+    for channel in state.channels.values_mut()
+    {
+        channel.users.clear();
+    }
+
+    state.auth.reset();
+    state.caps.reset();
+    state.isupport.reset();
+    state.nicks.reset();
+    state.rejoin = true;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// End of MOTD (or no MOTD), registration has finished
+fn registered(state: &mut State, link: &Link)
+{
+    state.auth.registered(link);
+
+    if !state.rejoin { return; }
+
+    for channel in state.joined.iter()
+    {
+        link.send(JOIN(channel.as_slice(), state.keys.get(channel).map(|k| k.as_slice())));
+    }
+    state.rejoin = false;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn join_channel(state: &mut State, link: &Link, channel: String)
+{
+    // while reconnecting remember the channel so it gets joined later
+    if !link.send(JOIN(channel.as_slice(), state.keys.get(&channel).map(|k| k.as_slice())))
+    {
+        state.joined.insert(channel);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn part_channel(state: &mut State, link: &Link, channel: String)
+{
+    if !link.send(PART(channel.as_slice(), None))
+    {
+        state.joined.remove(&channel);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The configuration file has been read again: join the channels added to it
+// and part the ones taken out
+fn configure(state: &mut State, link: &Link, channels: Vec<AutoJoin>)
+{
+    let old = state.configured.clone();
+
+    for c in channels.iter()
+    {
+        match c.key
+        {
+            Some(ref k) => { state.keys.insert(c.name.clone(), k.clone()); },
+            None        => { state.keys.remove(&c.name); }
+        }
+
+        if !old.iter().any(|o| o.name == c.name)
+        {
+            join_channel(state, link, c.name.clone());
+        }
+    }
+
+    for c in old.iter().filter(|o| !channels.iter().any(|c| c.name == o.name))
+    {
+        part_channel(state, link, c.name.clone());
+    }
+
+    state.configured = channels;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn handle_message(state: &mut State, link: &mut Link, time: Timespec, tags: Tags, msg: Message)
+{
+    match msg.command.as_slice()
+    {
+        "ERROR"   =>
+        {
+            let reason = msg.suffix.clone().unwrap_or("ERROR".to_string());
+            link.disconnected(reason.as_slice());
+        },
+        "001"     => state.nicks.welcome(&msg),
+        "005"     => state.isupport.handle(&msg),
+        "376"|"422" => registered(state, link),
+        "432"|"433"|"437" => nick_refused(state, link, time, &msg),
+        "TOPIC"   => set_topic(state, (time, msg)),
+        "JOIN"    => join(state, (time, msg)),
+        "PART"    => part(state, (time, msg)),
+        "KICK"    => kick(state, (time, msg)),
+        "QUIT"    => quit(state, (time, msg)),
+        "NICK"    => change_nick(state, (time, msg)),
+        "MODE"    => channel_mode(state, (time, msg)),
+        "353"     => names_reply(state, (time, msg)),
+        "366"     => end_names(state, (time, msg)),
+        "AWAY"    => set_away(state, &msg),
+        "ACCOUNT" => set_account(state, &msg),
+        "CAP"     => capability(state, link, &msg),
+        "AUTHENTICATE"|"900"|"902"|"903"|"904"|"905"|"906" => state.auth.handle(link, &msg),
+        "PRIVMSG" => { ctcp_request(state, link, &msg); add_message(state, (time, msg), tags) },
+        "NOTICE"  => { state.auth.notice(&msg); add_message(state, (time, msg), tags) },
+        _         => () //println!("{}", msg)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn respond(reply: &Option<Sender<Response>>, response: Response)
+{
+    if let Some(ref tx) = *reply
+    {
+        // the client may have given up waiting
+        let _ = tx.send_opt(response);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Returns false once the connection should be shut down
+fn handle_request(state: &mut State, link: &Link,
+                  reply: Option<Sender<Response>>, request: Request) -> bool
+{
+    match request
+    {
+        circ_comms::Request::ListChannels =>
+            respond(&reply, get_channels(state)),
+        circ_comms::Request::GetStatus(reader) =>
+            respond(&reply, get_status(state, link, reader.as_slice())),
+        circ_comms::Request::GetMessages(_, channel, reader) =>
+            respond(&reply, get_messages(state,
+                                         channel.as_slice(),
+                                         reader.as_slice(),
+                                         true)),
+        circ_comms::Request::Peek(_, channel, reader) =>
+            respond(&reply, get_messages(state,
+                                         channel.as_slice(),
+                                         reader.as_slice(),
+                                         false)),
+        circ_comms::Request::GetUsers(_, channel) =>
+            respond(&reply, get_users(state, channel.as_slice(), false)),
+        circ_comms::Request::GetUsersStatus(_, channel) =>
+            respond(&reply, get_users(state, channel.as_slice(), true)),
+        circ_comms::Request::Join(_, channel) => join_channel(state, link, channel),
+        circ_comms::Request::Part(_, channel) => part_channel(state, link, channel),
+        circ_comms::Request::ChangeNick(nick) => state.nicks.change(link, nick),
+        circ_comms::Request::SendMessage(_, channel, msg) =>
+            send_message(state, link, channel, msg),
+        circ_comms::Request::SearchHistory{channel, pattern, since,
+                                           until, user, limit, ..} =>
+            search_history(state, reply, channel, pattern.as_slice(), since, until, user, limit),
+        // subscribers arrive through Connection::subscribe instead
+        circ_comms::Request::Hello{..} |
+        circ_comms::Request::Subscribe(_, _) => (),
+        circ_comms::Request::Quit =>
+        {
+            link.send(QUIT(state.quit_message.as_ref().map(|m| m.as_slice())));
+            return false
+        }
+    }
+
+    true
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn process_task(network: config::Network,
+                log: Option<LogSettings>,
+                highlights: Highlights,
+                request_rx: Receiver<Job>,
+                subscribe_rx: Receiver<Subscriber>,
+                configure_rx: Receiver<Vec<AutoJoin>>)
 {
     spawn(move ||
           {
-              let mut channels = HashMap::new();
-              let server = Wrapper::new(&*tx);
+              let mut state = State::new(&network, log, highlights);
+              let mut link = Link::new(network.config, &network.credentials);
+
+              // channel to handle communication with the task receiving
+              // messages from the irc server
+              let (incoming_tx, incoming_rx) = channel();
 
-              server.identify().unwrap();
+              let mut timer = Timer::new().unwrap();
+              let tick = timer.periodic(Duration::seconds(1));
+
+              link.connect(&incoming_tx);
               loop
               {
-                  select!((time, msg) = rx.recv() =>
-                          match msg.command.as_slice()
+                  select!(incoming = incoming_rx.recv() =>
+                          match incoming
+                          {
+                              Incoming::Received(generation, time, tags, msg) =>
+                                  if link.is_current(generation)
+                                  {
+                                      link.received();
+                                      handle_message(&mut state, &mut link, time, tags, msg);
+                                  },
+                              Incoming::Closed(generation) =>
+                                  if link.is_current(generation)
+                                  {
+                                      link.disconnected("Connection closed");
+                                  }
+                          },
+
+                          () = tick.recv() =>
+                          if link.tick(&incoming_tx)
+                          {
+                              reconnected(&mut state);
+                          }
+                          else
                           {
-                              "ERROR"   => {println!("Error... {}", msg);},
-                              "TOPIC"   => set_topic(&mut channels, msg),
-                              "PRIVMSG"|"NOTICE" => add_message(&mut channels, (time, msg)),
-                              _         => () //println!("{}", msg)
+                              state.nicks.tick(&link);
+                              state.auth.tick();
                           },
 
-                          request = request_rx.recv() =>
-                          match request
+                          subscriber = subscribe_rx.recv() =>
+                          state.subscribers.push(subscriber),
+
+                          channels = configure_rx.recv() =>
+                          configure(&mut state, &link, channels),
+
+                          (request, reply) = request_rx.recv() =>
+                          if !handle_request(&mut state, &link, reply, request)
                           {
-                              circ_comms::Request::ListChannels =>
-                                  response_tx.send(get_channels(&channels)),
-                              circ_comms::Request::GetStatus =>
-                                  response_tx.send(get_status(&channels)),
-                              circ_comms::Request::GetMessages(channel) =>
-                                  response_tx.send(get_messages(&mut channels,
-                                                                channel.as_slice())),
-                              circ_comms::Request::GetUsers(_) =>
-                                  response_tx.send(circ_comms::Response::Users(Vec::new())),
-                              circ_comms::Request::Join(channel) =>
-                                  server.send(JOIN(channel.as_slice(), None)).unwrap(),
-                              circ_comms::Request::Part(channel) =>
-                                  server.send(PART(channel.as_slice(), None)).unwrap(),
-                              circ_comms::Request::SendMessage(channel, msg) =>
-                                  server.send(PRIVMSG(channel.as_slice(), msg.as_slice())).unwrap(),
-                              circ_comms::Request::Quit =>
-                                  { server.send(QUIT(None)).unwrap(); break }
+                              break;
                           });
               }
           });
@@ -196,38 +867,82 @@ fn process_task(rx: Receiver<(Timespec, Message)>,
 impl Connection
 {
     ///////////////////////////////////////////////////////////////////////////
-    pub fn new(config: Config) -> Connection
+    pub fn new(network: config::Network, log: Option<LogSettings>,
+               highlights: Highlights) -> Connection
     {
-        let irc_server = Arc::new(IrcServer::from_config(config).unwrap());
+        let name = network.name.clone();
 
-        // channel to handle communication with task receiving messages
-        // from the irc server
-        let (incoming_msg_tx, incoming_msg_rx) = channel();
+        // requests for the process task, each with its own reply channel
+        let (request_tx, request_rx) = channel();
 
-        // duplex to handle communication with process task
-        let ((request_tx, request_rx), (response_tx, response_rx)) = (channel(), channel());
+        // clients following channels are handed over separately
+        let (subscribe_tx, subscribe_rx) = channel();
 
-        // Start up the task to receive messages from the irc server
-        rx_task(irc_server.clone(), incoming_msg_tx);
+        // and so are the channels when the configuration is reloaded
+        let (configure_tx, configure_rx) = channel();
 
-        process_task(incoming_msg_rx, irc_server, response_tx, request_rx);
+        process_task(network, log, highlights, request_rx, subscribe_rx, configure_rx);
         
         
-        Connection{process_tx: request_tx,
-                   process_rx: response_rx}
+        Connection{network: name,
+                   process_tx: request_tx,
+                   subscribe_tx: subscribe_tx,
+                   configure_tx: configure_tx}
     }
 
     ///////////////////////////////////////////////////////////////////////////
-    pub fn request(&self, request: Request)
+    // The process task only goes away after QUIT, or if it has failed
+    fn stopped(&self) -> String
     {
-        self.process_tx.send(request);
+        format!("Network {} has stopped", self.network)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn subscribe(&self, subscriber: Subscriber) -> Result<(), String>
+    {
+        self.subscribe_tx.send_opt(subscriber).map_err(|_| self.stopped())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn configure(&self, channels: Vec<AutoJoin>) -> Result<(), String>
+    {
+        self.configure_tx.send_opt(channels).map_err(|_| self.stopped())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn request(&self, request: Request) -> Result<(), String>
+    {
+        self.process_tx.send_opt((request, None)).map_err(|_| self.stopped())
     }
 
     ///////////////////////////////////////////////////////////////////////////
     pub fn request_response(&self, request: Request) -> Response
     {
-        self.process_tx.send(request);
-        self.process_rx.recv()
+        let (tx, rx) = channel();
+
+        if self.process_tx.send_opt((request, Some(tx))).is_err()
+        {
+            return Response::Error(self.stopped());
+        }
+
+        match rx.recv_opt()
+        {
+            Ok(r)  => r,
+            Err(_) => Response::Error(self.stopped())
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Send QUIT and wait for the process task to finish, which drops the
+    // reply channel without answering
+    pub fn quit(&self)
+    {
+        let (tx, rx) = channel::<Response>();
+
+        if self.process_tx.send_opt((Request::Quit, Some(tx))).is_ok()
+        {
+            let _ = rx.recv_opt();
+        }
     }
 }    
 
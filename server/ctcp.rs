@@ -0,0 +1,218 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::ascii::AsciiExt;
+use std::collections::{BTreeMap, RingBuf};
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use link::Link;
+
+use irc::data::Command::NOTICE;
+
+///////////////////////////////////////////////////////////////////////////////
+static VERSION_REPLY: &'static str = "circ, a command line interface to IRC";
+
+// The requests answered unless the configuration says otherwise
+static ANSWERED: &'static [&'static str] = &["CLIENTINFO", "PING", "TIME", "VERSION"];
+
+///////////////////////////////////////////////////////////////////////////////
+// A CTCP message is the command and its parameters between \x01s, the last
+// of which some clients leave out
+pub fn parse(text: &str) -> Option<(String, String)>
+{
+    if !text.starts_with("\x01") || text.len() < 2
+    {
+        return None;
+    }
+
+    let inner = text.slice_from(1).trim_right_chars('\x01');
+
+    let (command, params) = match inner.find(' ')
+        {
+            Some(i) => (inner.slice_to(i), inner.slice_from(i + 1)),
+            None    => (inner, "")
+        };
+
+    if command.is_empty()
+    {
+        return None;
+    }
+
+    Some((command.to_ascii_upper(), params.to_string()))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// replies overrides what a request is answered with, None meaning it isn't
+// answered at all.  No more than limit replies are sent in interval seconds.
+#[deriving(Clone)]
+pub struct CtcpSettings
+{
+    pub replies: BTreeMap<String, Option<String>>,
+    pub limit: uint,
+    pub interval: i64
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl CtcpSettings
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> CtcpSettings
+    {
+        CtcpSettings{replies: BTreeMap::new(), limit: 3, interval: 10}
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Answering CTCP requests sent by other users
+pub struct Ctcp
+{
+    settings: CtcpSettings,
+    // when the replies of the last interval were sent
+    sent: RingBuf<Timespec>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Ctcp
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(settings: CtcpSettings) -> Ctcp
+    {
+        Ctcp{settings: settings, sent: RingBuf::new()}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Every request that gets an answer
+    fn commands(&self) -> Vec<String>
+    {
+        let mut commands: Vec<String> = ANSWERED.iter().map(|c| c.to_string()).collect();
+
+        for (command, reply) in self.settings.replies.iter()
+        {
+            commands.retain(|c| c != command);
+            if reply.is_some()
+            {
+                commands.push(command.clone());
+            }
+        }
+
+        commands.push("ACTION".to_string());
+        commands.sort();
+        commands
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn reply(&self, command: &str, params: &str) -> Option<String>
+    {
+        if let Some(reply) = self.settings.replies.get(command)
+        {
+            return reply.clone();
+        }
+
+        match command
+        {
+            "CLIENTINFO" => Some(self.commands().connect(" ")),
+            "PING"       => Some(params.to_string()),
+            "TIME"       => Some(time::now().rfc822().to_string()),
+            "VERSION"    => Some(VERSION_REPLY.to_string()),
+            _            => None
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Whether another reply can be sent without going over the limit
+    fn allowed(&mut self) -> bool
+    {
+        let now = time::get_time();
+        let start = now - Duration::seconds(self.settings.interval);
+
+        while self.sent.front().map_or(false, |t| *t <= start)
+        {
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() >= self.settings.limit
+        {
+            return false;
+        }
+
+        self.sent.push_back(now);
+        true
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A request from nick, answered with a NOTICE
+    pub fn request(&mut self, link: &Link, nick: &str, command: &str, params: &str)
+    {
+        let reply = match self.reply(command, params)
+            {
+                Some(r) => r,
+                None    => return
+            };
+
+        if !self.allowed()
+        {
+            println!("Not answering CTCP {} from {}, too many requests", command, nick);
+            return;
+        }
+
+        let text = if reply.is_empty() { format!("\x01{}\x01", command) }
+                   else                { format!("\x01{} {}\x01", command, reply) };
+
+        link.send(NOTICE(nick, text.as_slice()));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::parse;
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn parsed(command: &str, params: &str) -> Option<(String, String)>
+    {
+        Some((command.to_string(), params.to_string()))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn parses_command_and_params()
+    {
+        assert_eq!(parse("\x01ACTION waves\x01"), parsed("ACTION", "waves"));
+        assert_eq!(parse("\x01ping 1234 5678\x01"), parsed("PING", "1234 5678"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn accepts_a_missing_trailing_marker()
+    {
+        assert_eq!(parse("\x01VERSION"), parsed("VERSION", ""));
+        assert_eq!(parse("\x01ACTION waves"), parsed("ACTION", "waves"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn accepts_an_action_without_params()
+    {
+        assert_eq!(parse("\x01ACTION\x01"), parsed("ACTION", ""));
+        assert_eq!(parse("\x01ACTION \x01"), parsed("ACTION", ""));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn refuses_an_empty_command()
+    {
+        assert_eq!(parse("\x01\x01"), None);
+        assert_eq!(parse("\x01 waves\x01"), None);
+        assert_eq!(parse("\x01"), None);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn ignores_ordinary_text()
+    {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse(""), None);
+    }
+}
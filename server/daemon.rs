@@ -0,0 +1,147 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::io;
+use std::io::{File, Timer};
+use std::io::fs;
+use std::os;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+
+use libc;
+use libc::c_int;
+use libc::funcs::c95::stdlib::exit;
+use libc::funcs::posix01::signal::signal;
+use libc::funcs::posix88::fcntl::open;
+use libc::funcs::posix88::unistd::{close, dup2, fork, getpid, setsid};
+
+///////////////////////////////////////////////////////////////////////////////
+pub enum Signal
+{
+    // SIGTERM or SIGINT, quit irc and stop
+    Terminate,
+    // SIGHUP, read the configuration file again
+    Reload
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Signal handlers can't do much safely, so they only note what arrived for
+// the task started by signals() to pass on
+static PENDING: AtomicUint = INIT_ATOMIC_UINT;
+
+static TERMINATE: uint = 1;
+static RELOAD: uint = 2;
+
+///////////////////////////////////////////////////////////////////////////////
+extern "C" fn handler(signum: c_int)
+{
+    let flag = if signum == libc::SIGHUP { RELOAD } else { TERMINATE };
+    PENDING.fetch_or(flag, SeqCst);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Start handling SIGTERM, SIGINT and SIGHUP, until told to stop
+pub fn signals(stop: Receiver<()>) -> Receiver<Signal>
+{
+    unsafe
+    {
+        for &s in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP].iter()
+        {
+            signal(s, handler as libc::sighandler_t);
+        }
+    }
+
+    let (tx, rx) = channel();
+
+    spawn(move ||
+          {
+              let mut timer = Timer::new().unwrap();
+              let tick = timer.periodic(Duration::milliseconds(200));
+
+              loop
+              {
+                  select!(() = tick.recv() => (),
+                          _ = stop.recv_opt() => break);
+
+                  let pending = PENDING.swap(0, SeqCst);
+
+                  if pending & RELOAD != 0 && tx.send_opt(Signal::Reload).is_err()
+                  {
+                      break;
+                  }
+                  if pending & TERMINATE != 0 && tx.send_opt(Signal::Terminate).is_err()
+                  {
+                      break;
+                  }
+              }
+          });
+
+    rx
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Where the output goes when running as a daemon without --log
+pub fn default_log() -> Path
+{
+    match os::homedir()
+    {
+        Some(home) => home.join_many(&[".circd", "circd.log"]),
+        None       => Path::new("/tmp/circd.log")
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Detach from the terminal, leaving the parent to exit, with output appended
+// to log.  This has to happen before any tasks are started.
+pub fn daemonize(log: &Path) -> Result<(), String>
+{
+    if let Err(e) = fs::mkdir_recursive(&log.dir_path(), io::USER_RWX)
+    {
+        return Err(format!("Unable to create {}: {}", log.dir_path().display(), e));
+    }
+
+    unsafe
+    {
+        let output = log.with_c_str(|p| open(p, libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                                             libc::S_IRUSR | libc::S_IWUSR));
+        if output < 0
+        {
+            return Err(format!("Unable to open {}: {}", log.display(), os::last_os_error()));
+        }
+
+        let null = "/dev/null".with_c_str(|p| open(p, libc::O_RDONLY, 0));
+        if null < 0
+        {
+            return Err(format!("Unable to open /dev/null: {}", os::last_os_error()));
+        }
+
+        match fork()
+        {
+            -1 => return Err(format!("Unable to fork: {}", os::last_os_error())),
+            0  => (),
+            _  => exit(0)
+        }
+
+        setsid();
+
+        dup2(null, libc::STDIN_FILENO);
+        dup2(output, libc::STDOUT_FILENO);
+        dup2(output, libc::STDERR_FILENO);
+        close(null);
+        close(output);
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Written next to the socket unless --pid-file says otherwise
+pub fn default_pid_file(socket: &Path) -> Path
+{
+    socket.dir_path().join("circd.pid")
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn write_pid(path: &Path) -> io::IoResult<()>
+{
+    let pid = unsafe { getpid() };
+    File::create(path).write_line(pid.to_string().as_slice())
+}
@@ -0,0 +1,126 @@
+///////////////////////////////////////////////////////////////////////////////
+use regex::Regex;
+
+///////////////////////////////////////////////////////////////////////////////
+// What makes a message mention the user: their current nick, any of the
+// keywords (both as whole words, ignoring case) or a match for any of the
+// patterns
+#[deriving(Clone)]
+pub struct Highlights
+{
+    keywords: Vec<String>,
+    patterns: Vec<Regex>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn is_word_char(c: char) -> bool
+{
+    c.is_alphanumeric() || "_-[]\\`^{}|".contains_char(c)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Whether word appears in text without being part of a longer word (or nick)
+fn has_word(text: &str, word: &str) -> bool
+{
+    if word.is_empty()
+    {
+        return false;
+    }
+
+    let text = text.to_lowercase();
+    let word = word.to_lowercase();
+    let text = text.as_slice();
+
+    let mut start = 0;
+    while let Some(i) = text.slice_from(start).find_str(word.as_slice())
+    {
+        let begin = start + i;
+        let end = begin + word.len();
+
+        let before = text.slice_to(begin).chars().next_back().map_or(false, is_word_char);
+        let after = text.slice_from(end).chars().next().map_or(false, is_word_char);
+
+        if !before && !after
+        {
+            return true;
+        }
+
+        start = begin + text.char_range_at(begin).next - begin;
+    }
+
+    false
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Highlights
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(keywords: Vec<String>, patterns: Vec<Regex>) -> Highlights
+    {
+        Highlights{keywords: keywords, patterns: patterns}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn matches(&self, nick: &str, text: &str) -> bool
+    {
+        has_word(text, nick) ||
+            self.keywords.iter().any(|k| has_word(text, k.as_slice())) ||
+            self.patterns.iter().any(|p| p.is_match(text))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{Highlights, has_word};
+
+    use regex::Regex;
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn finds_an_addressed_nick()
+    {
+        assert!(has_word("bob: are you there?", "bob"));
+        assert!(has_word("thanks @bob", "bob"));
+        assert!(has_word("bob, bob", "bob"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn ignores_part_of_a_longer_word()
+    {
+        assert!(!has_word("bobby is here", "bob"));
+        assert!(!has_word("bob_ is here", "bob"));
+        assert!(!has_word("kabob", "bob"));
+        // a later whole word still counts
+        assert!(has_word("bobby and bob", "bob"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn ignores_case()
+    {
+        assert!(has_word("BOB: hi", "bob"));
+        assert!(has_word("hi bob", "Bob"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn never_matches_an_empty_word()
+    {
+        assert!(!has_word("anything", ""));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn matches_keywords_and_patterns()
+    {
+        let highlights = Highlights::new(vec!["rust".to_string()],
+                                         vec![Regex::new("build (failed|broke)").unwrap()]);
+
+        assert!(highlights.matches("bob", "Rust 1.0 soon"));
+        assert!(highlights.matches("bob", "the build broke again"));
+        assert!(!highlights.matches("bob", "rusty nails"));
+    }
+}
@@ -0,0 +1,148 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::io;
+use std::io::{BufferedReader, File, fs};
+use std::io::fs::PathExtensions;
+
+use regex::Regex;
+use serialize::json;
+
+use time;
+use time::Timespec;
+
+use circ_comms;
+
+///////////////////////////////////////////////////////////////////////////////
+// History is kept next to the human readable log as one json encoded
+// circ_comms::Message per line, <directory>/<date>.history, so it can be
+// searched no matter how the log is formatted.
+pub static EXTENSION: &'static str = "history";
+
+///////////////////////////////////////////////////////////////////////////////
+pub struct Query
+{
+    pub pattern: Regex,
+    pub since: Option<Timespec>,
+    pub until: Option<Timespec>,
+    pub user: Option<String>,
+    pub limit: Option<uint>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Query
+{
+    ///////////////////////////////////////////////////////////////////////////
+    fn matches(&self, msg: &circ_comms::Message) -> bool
+    {
+        if let Some(since) = self.since
+        {
+            if msg.time < since { return false; }
+        }
+
+        if let Some(until) = self.until
+        {
+            if msg.time > until { return false; }
+        }
+
+        if let Some(ref user) = self.user
+        {
+            let nick = msg.user.as_slice().split('!').next().unwrap();
+            if nick != user.as_slice() { return false; }
+        }
+
+        self.pattern.is_match(msg.msg.as_slice())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Whether a day's file can hold anything in the requested time range
+    fn covers(&self, date: &str) -> bool
+    {
+        let day = |t: Timespec| format!("{}", time::at(t).strftime("%Y-%m-%d").unwrap());
+
+        match self.since
+        {
+            Some(t) if date < day(t).as_slice() => return false,
+            _ => ()
+        }
+
+        match self.until
+        {
+            Some(t) if date > day(t).as_slice() => return false,
+            _ => ()
+        }
+
+        true
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn encode(msg: &circ_comms::Message) -> String
+{
+    json::encode(msg)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn search_file(path: &Path, query: &Query, found: &mut Vec<circ_comms::Message>) -> io::IoResult<()>
+{
+    let mut reader = BufferedReader::new(try!(File::open(path)));
+
+    for line in reader.lines()
+    {
+        let line = try!(line);
+
+        match json::decode::<circ_comms::Message>(line.as_slice().trim())
+        {
+            Ok(m)  => if query.matches(&m) { found.push(m) },
+            Err(e) => println!("Skipping bad history line in {}: {}", path.display(), e)
+        }
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Search the history of one channel, oldest match first.  With a limit only
+// the most recent matches are returned, so the files are searched newest
+// first and older ones aren't read once there are enough.
+pub fn search(directory: &Path, query: &Query) -> io::IoResult<Vec<circ_comms::Message>>
+{
+    let mut found: Vec<circ_comms::Message> = Vec::new();
+
+    if !directory.exists()
+    {
+        return Ok(found);
+    }
+
+    let mut files: Vec<Path> = try!(fs::readdir(directory)).into_iter()
+        .filter(|p| p.extension_str() == Some(EXTENSION))
+        .filter(|p| match p.filestem_str() { Some(d) => query.covers(d), None => false })
+        .collect();
+
+    // file names are dates so this puts them in time order
+    files.sort();
+
+    for f in files.iter().rev()
+    {
+        let mut day = Vec::new();
+        try!(search_file(f, query, &mut day));
+
+        if let Some(limit) = query.limit
+        {
+            let wanted = limit - found.len();
+            if day.len() > wanted
+            {
+                let start = day.len() - wanted;
+                day = day.slice_from(start).to_vec();
+            }
+        }
+
+        day.extend(found.into_iter());
+        found = day;
+
+        if query.limit.map_or(false, |l| found.len() >= l)
+        {
+            break;
+        }
+    }
+
+    Ok(found)
+}
@@ -0,0 +1,95 @@
+///////////////////////////////////////////////////////////////////////////////
+// Users whose messages are dropped, as nick!user@host masks where * matches
+// anything and ? any one character, compared ignoring case.  A mask without
+// a ! is only a nick.
+#[deriving(Clone)]
+pub struct Ignores
+{
+    masks: Vec<Vec<char>>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn glob(pattern: &[char], text: &[char]) -> bool
+{
+    match pattern.first()
+    {
+        None       => text.is_empty(),
+        Some(&'*') => glob(pattern.tail(), text) ||
+                      (!text.is_empty() && glob(pattern, text.tail())),
+        Some(&'?') => !text.is_empty() && glob(pattern.tail(), text.tail()),
+        Some(&c)   => text.first() == Some(&c) && glob(pattern.tail(), text.tail())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Ignores
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(masks: Vec<String>) -> Ignores
+    {
+        let masks = masks.iter()
+                         .map(|m| if m.as_slice().contains_char('!') { m.clone() }
+                                  else { format!("{}!*@*", m) })
+                         .map(|m| m.as_slice().to_lowercase().as_slice().chars().collect())
+                         .collect();
+
+        Ignores{masks: masks}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Whether a message prefix (nick!user@host) belongs to an ignored user
+    pub fn matches(&self, prefix: &str) -> bool
+    {
+        let prefix: Vec<char> = prefix.to_lowercase().as_slice().chars().collect();
+
+        self.masks.iter().any(|m| glob(m.as_slice(), prefix.as_slice()))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::Ignores;
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn ignores(masks: &[&str]) -> Ignores
+    {
+        Ignores::new(masks.iter().map(|m| m.to_string()).collect())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn star_matches_any_run_of_characters()
+    {
+        let i = ignores(&["*!*@spam.example.org", "bot*!*@*"]);
+
+        assert!(i.matches("anyone!user@spam.example.org"));
+        assert!(i.matches("bot!b@host"));
+        assert!(i.matches("botty!b@host"));
+        assert!(!i.matches("anyone!user@example.org"));
+        assert!(!i.matches("robot!b@host"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn question_mark_matches_one_character()
+    {
+        let i = ignores(&["bot?!*@*"]);
+
+        assert!(i.matches("bot1!b@host"));
+        assert!(!i.matches("bot!b@host"));
+        assert!(!i.matches("bot12!b@host"));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn a_nick_alone_matches_any_user_and_host_ignoring_case()
+    {
+        let i = ignores(&["Troll"]);
+
+        assert!(i.matches("troll!t@somewhere"));
+        assert!(i.matches("TROLL!x@elsewhere"));
+        assert!(!i.matches("troll2!t@somewhere"));
+    }
+}
@@ -1,44 +1,148 @@
 ///////////////////////////////////////////////////////////////////////////////
 
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, RingBuf};
+
 use time::Timespec;
 
+use circ_comms;
+use ctcp;
 use irc::data::message::Message;
+use isupport::same_nick;
+
+use logger::ChannelLog;
 
 ///////////////////////////////////////////////////////////////////////////////
-#[deriving(Show)]
+#[deriving(Show, Clone)]
 pub struct User
 {
     pub name   : String,
     pub client : String,
-    pub address: String
+    pub address: String,
+    pub mode   : String,
+    pub away   : Option<String>,
+    pub account: Option<String>
 }
 
-/*
+///////////////////////////////////////////////////////////////////////////////
 impl User
 {
-    pub fn parse(data: String) -> User
+    ///////////////////////////////////////////////////////////////////////////
+    // Parse a NAMES entry or message prefix: [modes]nick[!client[@address]].
+    // prefixes are the membership prefixes the server uses, highest first.
+    pub fn parse(data: &str, prefixes: &str) -> User
+    {
+        let mode: String = data.chars().take_while(|c| prefixes.contains_char(*c)).collect();
+        let rest = data.slice_from(mode.len());
+
+        let (name, host) = match rest.find('!')
+            {
+                Some(i) => (rest.slice_to(i), rest.slice_from(i + 1)),
+                None    => (rest, "")
+            };
+        let (client, address) = match host.find('@')
+            {
+                Some(i) => (host.slice_to(i), host.slice_from(i + 1)),
+                None    => (host, "")
+            };
+
+        User{name: name.to_string(), client: client.to_string(),
+             address: address.to_string(), mode: mode, away: None, account: None}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn rank(&self, prefixes: &str) -> uint
+    {
+        match self.mode.as_slice().chars().next().and_then(|c| prefixes.find(c))
+        {
+            Some(i) => i,
+            None    => prefixes.len()
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Nick with the highest ranked prefix, e.g. "@nick"
+    pub fn display(&self) -> String
+    {
+        match self.mode.as_slice().chars().next()
+        {
+            Some(c) => format!("{}{}", c, self.name),
+            None    => self.name.clone()
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn status(&self) -> circ_comms::UserStatus
+    {
+        circ_comms::UserStatus{name: self.display(), away: self.away.clone(),
+                               account: self.account.clone()}
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Convert a message received from the irc server into what clients are sent,
+// with CTCP taken apart
+pub fn comms_message(time: Timespec, msg: &Message) -> circ_comms::Message
+{
+    let user = match msg.prefix
+        {
+            Some(ref p) => p.as_slice(),
+            None        => "Unknown User"
+        };
+    let text = match msg.suffix
+        {
+            Some(ref m) => m.as_slice(),
+            None        => "No message"
+        };
+
+    match ctcp::parse(text)
     {
-        User{name: String::new(), client: String::new(), address: String::new()}
+        Some((command, params)) =>
+        {
+            let mut message = circ_comms::Message::new(time, user, params.as_slice());
+            message.kind = Some(if command.as_slice() == "ACTION" { circ_comms::MessageKind::Action }
+                                else { circ_comms::MessageKind::Ctcp(command) });
+            message
+        },
+        None => circ_comms::Message::new(time, user, text)
     }
 }
-*/
+
 ///////////////////////////////////////////////////////////////////////////////
+// Number of messages kept per channel, read or not, unless the configuration
+// says otherwise
+pub static HISTORY_LIMIT: uint = 1000;
+
+///////////////////////////////////////////////////////////////////////////////
+// Messages are never removed when read.  Each reader has a cursor holding the
+// sequence number of the next message it hasn't seen; a reader that has never
+// asked before hasn't seen anything still kept.  The sequence numbers of the
+// messages mentioning the user are kept too, so each reader's unread mentions
+// can be counted.
 #[deriving(Show)]
 pub struct Channel
 {
     pub name: String,
     pub topic: String,
     pub users: Vec<User>,
-    pub messages: Vec<(Timespec, Message)>
+    pub messages: RingBuf<circ_comms::Message>,
+    names_pending: bool,
+    first: uint,
+    mentions: RingBuf<uint>,
+    readers: HashMap<String, uint>,
+    log: Option<ChannelLog>,
+    limit: uint
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 impl Channel
 {
     ///////////////////////////////////////////////////////////////////////////
-    pub fn new(name: &str) -> Channel
+    pub fn new(name: &str, log: Option<ChannelLog>, limit: uint) -> Channel
     {
-        Channel{name: name.to_string(), topic: String::new(), users: Vec::new(), messages: Vec::new()}
+        Channel{name: name.to_string(), topic: String::new(), users: Vec::new(),
+                messages: RingBuf::new(), names_pending: false, first: 0, mentions: RingBuf::new(),
+                readers: HashMap::new(), log: log, limit: limit}
     }
 
     ///////////////////////////////////////////////////////////////////////////
@@ -48,21 +152,253 @@ impl Channel
     }
 
     ///////////////////////////////////////////////////////////////////////////
-    pub fn add(&mut self, msg: (Timespec, Message))
+    // A NAMES reply can be split over several RPL_NAMREPLY (353) messages.
+    // The first one replaces whatever was known, RPL_ENDOFNAMES (366)
+    // finishes the list.
+    pub fn add_names(&mut self, names: &str, prefixes: &str)
     {
-        self.messages.push(msg);
-        // also write it to a log file at this point?
+        if !self.names_pending
+        {
+            self.users.clear();
+            self.names_pending = true;
+        }
+
+        for name in names.words()
+        {
+            let user = User::parse(name, prefixes);
+            self.remove_user(user.name.as_slice());
+            self.users.push(user);
+        }
     }
 
     ///////////////////////////////////////////////////////////////////////////
-    //pub fn unread_msgs(&mut self) 
-    //pub fn recent_msgs(&mut self, seconds: u32)
-    //pub fn last_msgs(&mut self, num: u32)
-    //
+    pub fn end_names(&mut self)
+    {
+        self.names_pending = false;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn has_user(&self, nick: &str) -> bool
+    {
+        self.users.iter().any(|u| same_nick(u.name.as_slice(), nick))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn add_user(&mut self, user: User)
+    {
+        self.remove_user(user.name.as_slice());
+        self.users.push(user);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // From away-notify, None when the user is back
+    pub fn set_away(&mut self, nick: &str, away: Option<String>) -> bool
+    {
+        match self.users.iter_mut().find(|u| same_nick(u.name.as_slice(), nick))
+        {
+            Some(u) => { u.away = away; true },
+            None    => false
+        }
+    }
+
     ///////////////////////////////////////////////////////////////////////////
-    pub fn clear(&mut self)
+    // From account-notify, None when the user has logged out
+    pub fn set_account(&mut self, nick: &str, account: Option<String>) -> bool
     {
-        self.messages.clear();
+        match self.users.iter_mut().find(|u| same_nick(u.name.as_slice(), nick))
+        {
+            Some(u) => { u.account = account; true },
+            None    => false
+        }
     }
 
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn remove_user(&mut self, nick: &str) -> bool
+    {
+        let before = self.users.len();
+        self.users.retain(|u| !same_nick(u.name.as_slice(), nick));
+        before != self.users.len()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn rename_user(&mut self, old: &str, new: &str) -> bool
+    {
+        match self.users.iter_mut().find(|u| same_nick(u.name.as_slice(), old))
+        {
+            Some(u) => { u.name = new.to_string(); true },
+            None    => false
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Apply a +o/-o, +v/-v... channel mode to a user
+    pub fn set_user_mode(&mut self, nick: &str, prefix: char, add: bool, prefixes: &str)
+    {
+        if let Some(u) = self.users.iter_mut().find(|u| same_nick(u.name.as_slice(), nick))
+        {
+            let mut mode: String = u.mode.as_slice().chars().filter(|&c| c != prefix).collect();
+            if add
+            {
+                mode.push(prefix);
+            }
+            // keep the highest ranked prefix first
+            let mut chars: Vec<char> = mode.as_slice().chars().collect();
+            chars.sort_by(|a, b| prefixes.find(*a).cmp(&prefixes.find(*b)));
+            u.mode = chars.into_iter().collect();
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Users sorted by rank then nick
+    pub fn user_list(&self, prefixes: &str) -> Vec<circ_comms::UserStatus>
+    {
+        let mut users: Vec<&User> = self.users.iter().collect();
+        users.sort_by(|a, b| (a.rank(prefixes), a.name.as_slice()).cmp(&(b.rank(prefixes), b.name.as_slice())));
+        users.iter().map(|u| u.status()).collect()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Returns the message as clients are given it
+    pub fn add(&mut self, msg: (Timespec, Message), tags: BTreeMap<String, String>,
+               mention: bool) -> circ_comms::Message
+    {
+        self.log_event(&msg);
+
+        let mut message = comms_message(msg.0, &msg.1);
+        if !tags.is_empty()
+        {
+            message.tags = Some(tags);
+        }
+        self.messages.push_back(message.clone());
+
+        if mention
+        {
+            self.mentions.push_back(self.first + self.messages.len() - 1);
+        }
+
+        if self.messages.len() > self.limit
+        {
+            self.messages.pop_front();
+            self.first += 1;
+
+            if self.mentions.front() == Some(&(self.first - 1))
+            {
+                self.mentions.pop_front();
+            }
+        }
+
+        message
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Record something that happened on the channel (join, part, topic...)
+    // without adding it to the unread messages
+    pub fn log_event(&mut self, msg: &(Timespec, Message))
+    {
+        if let Some(ref mut log) = self.log
+        {
+            log.write(msg.0, &msg.1);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Index into messages of the first message the reader hasn't seen
+    fn cursor(&self, reader: &str) -> uint
+    {
+        match self.readers.get(&reader.to_string())
+        {
+            Some(&seq) => cmp::max(seq, self.first) - self.first,
+            None       => 0
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn unread_count(&self, reader: &str) -> uint
+    {
+        self.messages.len() - self.cursor(reader)
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn unread_mentions(&self, reader: &str) -> uint
+    {
+        let start = self.first + self.cursor(reader);
+        self.mentions.iter().filter(|&&seq| seq >= start).count()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn unread_msgs(&self, reader: &str) -> Vec<&circ_comms::Message>
+    {
+        self.messages.iter().skip(self.cursor(reader)).collect()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn mark_read(&mut self, reader: &str)
+    {
+        let end = self.first + self.messages.len();
+        self.readers.insert(reader.to_string(), end);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The last message was sent by us, so it isn't unread for the readers
+    // that had seen everything before it
+    pub fn read_own(&mut self)
+    {
+        let end = self.first + self.messages.len();
+
+        for seq in self.readers.values_mut()
+        {
+            if *seq + 1 == end
+            {
+                *seq = end;
+            }
+        }
+    }
+
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::Channel;
+
+    use std::collections::BTreeMap;
+    use time::Timespec;
+
+    use irc::data::message::Message;
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn add(channel: &mut Channel, text: &str, mention: bool)
+    {
+        let msg = Message::new(Some("bob!b@example.org"), "PRIVMSG", Some(vec!["#rust"]), Some(text));
+        channel.add((Timespec::new(0, 0), msg), BTreeMap::new(), mention);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn drops_the_oldest_messages_and_their_mentions_over_the_limit()
+    {
+        let mut channel = Channel::new("#rust", None, 3);
+
+        add(&mut channel, "0", true);
+        add(&mut channel, "1", true);
+        channel.mark_read("caught-up-early");
+        add(&mut channel, "2", false);
+        add(&mut channel, "3", true);
+        add(&mut channel, "4", false);
+
+        assert_eq!(channel.first, 2);
+        assert_eq!(channel.messages.iter().map(|m| m.msg.clone()).collect::<Vec<String>>(),
+                   vec!["2".to_string(), "3".to_string(), "4".to_string()]);
+
+        // only what is still kept counts
+        assert_eq!(channel.unread_count("caught-up-early"), 3);
+        assert_eq!(channel.unread_mentions("caught-up-early"), 1);
+        assert_eq!(channel.unread_count("new"), 3);
+        assert_eq!(channel.unread_mentions("new"), 1);
+
+        channel.mark_read("new");
+        assert_eq!(channel.unread_count("new"), 0);
+        assert_eq!(channel.unread_mentions("new"), 0);
+    }
 }
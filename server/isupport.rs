@@ -0,0 +1,236 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::ascii::AsciiExt;
+
+use irc::data::Message;
+
+///////////////////////////////////////////////////////////////////////////////
+// What is assumed until the server says otherwise, the modes of RFC 2811
+// plus the common owner, admin and halfop prefixes
+static DEFAULT_CHANMODES: &'static str = "beI,k,l,imnpst";
+static DEFAULT_PREFIX: &'static str = "(qaohv)~&@%+";
+static DEFAULT_NICKLEN: uint = 9;
+
+///////////////////////////////////////////////////////////////////////////////
+// Nicks compare case-insensitively, with {}|^ the lower case of []\~ as in
+// the rfc1459 casemapping nearly every network uses
+pub fn irc_lower(nick: &str) -> String
+{
+    nick.chars().map(|c| match c
+        {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase()
+        }).collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+pub fn same_nick(a: &str, b: &str) -> bool
+{
+    irc_lower(a) == irc_lower(b)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What a mode character in a channel MODE does with the parameters
+#[deriving(PartialEq, Show)]
+pub enum ModeKind
+{
+    // gives a user one of the PREFIX ranks, such as +o giving @
+    Prefix(char),
+    // CHANMODES A (lists) and B (keys) always take a parameter, C (limits)
+    // only when set and D never does
+    Param,
+    NoParam
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The channel modes a server announces in RPL_ISUPPORT (005)
+pub struct ISupport
+{
+    // the mode letters in each of the four CHANMODES groups
+    chanmodes: Vec<String>,
+    // mode letters and their prefixes, highest rank first
+    modes: String,
+    prefixes: String,
+    nicklen: uint
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl ISupport
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> ISupport
+    {
+        let mut isupport = ISupport{chanmodes: Vec::new(), modes: String::new(), prefixes: String::new(),
+                                    nicklen: DEFAULT_NICKLEN};
+        isupport.set_chanmodes(DEFAULT_CHANMODES);
+        isupport.set_prefix(DEFAULT_PREFIX);
+        isupport
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Every connection starts from the defaults again
+    pub fn reset(&mut self)
+    {
+        *self = ISupport::new();
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // CHANMODES=A,B,C,D
+    fn set_chanmodes(&mut self, value: &str)
+    {
+        let mut groups: Vec<String> = value.split(',').map(|g| g.to_string()).collect();
+        while groups.len() < 4 { groups.push(String::new()); }
+        self.chanmodes = groups;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // PREFIX=(modes)prefixes, or nothing at all when there are none
+    fn set_prefix(&mut self, value: &str)
+    {
+        self.modes = String::new();
+        self.prefixes = String::new();
+
+        if !value.starts_with("(")
+        {
+            return;
+        }
+
+        if let Some(i) = value.find(')')
+        {
+            let modes = value.slice(1, i);
+            let prefixes = value.slice_from(i + 1);
+
+            if modes.char_len() == prefixes.char_len()
+            {
+                self.modes = modes.to_string();
+                self.prefixes = prefixes.to_string();
+            }
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // RPL_ISUPPORT: <me> TOKEN[=value]... :are supported by this server
+    pub fn handle(&mut self, msg: &Message)
+    {
+        for token in msg.args.iter().skip(1)
+        {
+            let token = token.as_slice();
+            let (key, value) = match token.find('=')
+                {
+                    Some(i) => (token.slice_to(i), token.slice_from(i + 1)),
+                    None    => (token, "")
+                };
+
+            match key
+            {
+                "CHANMODES" => self.set_chanmodes(value),
+                "PREFIX"    => self.set_prefix(value),
+                "NICKLEN"   => self.nicklen = from_str(value).unwrap_or(DEFAULT_NICKLEN),
+                _           => ()
+            }
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The membership prefixes, highest rank first
+    pub fn prefixes(&self) -> &str
+    {
+        self.prefixes.as_slice()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The longest nick the server accepts
+    pub fn nicklen(&self) -> uint
+    {
+        self.nicklen
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn mode_kind(&self, mode: char, add: bool) -> ModeKind
+    {
+        if let Some(i) = self.modes.as_slice().chars().position(|m| m == mode)
+        {
+            return ModeKind::Prefix(self.prefixes.as_slice().chars().nth(i).unwrap());
+        }
+
+        let group = self.chanmodes.iter().position(|g| g.as_slice().contains_char(mode));
+
+        match group
+        {
+            Some(0) | Some(1) => ModeKind::Param,
+            Some(2) if add    => ModeKind::Param,
+            _                 => ModeKind::NoParam
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{ISupport, ModeKind, same_nick};
+
+    use irc::data::Message;
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn isupport(tokens: &str) -> ISupport
+    {
+        let line = format!(":irc.example.org 005 me {} :are supported by this server\r\n", tokens);
+        let mut isupport = ISupport::new();
+        isupport.handle(&from_str::<Message>(line.as_slice()).unwrap());
+        isupport
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn parses_prefix()
+    {
+        let i = isupport("PREFIX=(ov)@+");
+        assert_eq!(i.prefixes(), "@+");
+        assert_eq!(i.mode_kind('o', true), ModeKind::Prefix('@'));
+        assert_eq!(i.mode_kind('v', false), ModeKind::Prefix('+'));
+        // no longer a prefix, and not in CHANMODES either
+        assert_eq!(i.mode_kind('h', true), ModeKind::NoParam);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn ignores_bad_prefix()
+    {
+        assert_eq!(isupport("PREFIX=").prefixes(), "");
+        assert_eq!(isupport("PREFIX=(ov)@").prefixes(), "");
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn parses_chanmodes()
+    {
+        let i = isupport("CHANMODES=beI,kf,lj,imnpst");
+        assert_eq!(i.mode_kind('b', true), ModeKind::Param);
+        assert_eq!(i.mode_kind('f', false), ModeKind::Param);
+        assert_eq!(i.mode_kind('j', true), ModeKind::Param);
+        assert_eq!(i.mode_kind('j', false), ModeKind::NoParam);
+        assert_eq!(i.mode_kind('m', true), ModeKind::NoParam);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn parses_nicklen()
+    {
+        assert_eq!(ISupport::new().nicklen(), 9);
+        assert_eq!(isupport("CHANTYPES=# NICKLEN=30").nicklen(), 30);
+        assert_eq!(isupport("NICKLEN=many").nicklen(), 9);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn compares_nicks_with_rfc1459_casemapping()
+    {
+        assert!(same_nick("Nick", "nICK"));
+        assert!(same_nick("nick[away]", "NICK{AWAY}"));
+        assert!(same_nick("a\\b~", "A|B^"));
+        assert!(!same_nick("nick", "nick_"));
+    }
+}
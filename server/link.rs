@@ -0,0 +1,522 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{BufferedReader, BufferedWriter, IoError, IoResult, OtherIoError};
+use std::io::net::tcp::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use circ_comms::{ConnectionState, TlsSettings, connect_tls};
+use config::Credentials;
+
+use irc::conn::{Connection, NetStream};
+use irc::data::{Command, Config, Message};
+use irc::data::Command::PING;
+use irc::server::{IrcServer, Server, NetIrcServer};
+
+use openssl::ssl::SslStream;
+
+///////////////////////////////////////////////////////////////////////////////
+// Seconds without hearing from the server before checking it with a PING,
+// and before giving up on the connection
+static PING_INTERVAL: i64 = 120;
+static PING_TIMEOUT: i64 = 240;
+
+// Longest wait in seconds between reconnection attempts
+static MAX_BACKOFF: i64 = 300;
+
+///////////////////////////////////////////////////////////////////////////////
+// What the task reading from the irc server passes on.  Each connection made
+// has its own generation so anything left over from an abandoned connection
+// can be ignored.
+pub enum Incoming
+{
+    Received(uint, Timespec, Tags, Message),
+    Closed(uint)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// IRCv3 message tags, @key=value;key;...
+pub type Tags = BTreeMap<String, String>;
+
+///////////////////////////////////////////////////////////////////////////////
+fn unescape(value: &str) -> String
+{
+    let mut result = String::new();
+    let mut chars = value.chars();
+
+    loop
+    {
+        match chars.next()
+        {
+            Some('\\') => match chars.next()
+                {
+                    Some(':') => result.push(';'),
+                    Some('s') => result.push(' '),
+                    Some('r') => result.push('\r'),
+                    Some('n') => result.push('\n'),
+                    Some(c)   => result.push(c),
+                    None      => break
+                },
+            Some(c) => result.push(c),
+            None    => break
+        }
+    }
+
+    result
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The irc crate doesn't know about tags and takes "@tags" for the command, so
+// the line is put back together and parsed again without them
+fn split_tags(message: Message) -> (Tags, Message)
+{
+    let mut tags = BTreeMap::new();
+
+    if !message.command.as_slice().starts_with("@")
+    {
+        return (tags, message);
+    }
+
+    let line = message.into_string();
+    let line = line.as_slice().trim_right_chars(['\r', '\n'].as_slice());
+
+    let (raw, rest) = match line.find(' ')
+        {
+            Some(i) => (line.slice(1, i), line.slice_from(i + 1)),
+            None    => return (tags, message)
+        };
+
+    let message = match from_str::<Message>(format!("{}\r\n", rest.trim_left()).as_slice())
+        {
+            Some(m) => m,
+            None    => return (tags, message)
+        };
+
+    for tag in raw.split(';').filter(|t| !t.is_empty())
+    {
+        match tag.find('=')
+        {
+            Some(i) => tags.insert(tag.slice_to(i).to_string(), unescape(tag.slice_from(i + 1))),
+            None    => tags.insert(tag.to_string(), String::new())
+        };
+    }
+
+    (tags, message)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// server-time: time=2014-12-01T18:30:00.000Z, always UTC
+pub fn server_time(tags: &Tags) -> Option<Timespec>
+{
+    let value = match tags.get("time") { Some(v) => v.as_slice(), None => return None };
+
+    let seconds = value.split('.').next().unwrap().trim_right_chars('Z');
+
+    match time::strptime(seconds, "%Y-%m-%dT%H:%M:%S")
+    {
+        Ok(tm) => Some(tm.to_timespec()),
+        Err(_) => None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// What is sent on connecting, in order.  Capabilities are asked for before
+// registering, so a server that knows about them holds registration back
+// until CAP END, which Capabilities or Auth send once negotiation is over.
+// The irc crate's identify() can't be used as it sends CAP END straight away.
+fn registration(config: &Config) -> Vec<Message>
+{
+    let nick = config.nickname.as_slice();
+    let user = if config.username.is_empty() { nick } else { config.username.as_slice() };
+    let real = if config.realname.is_empty() { nick } else { config.realname.as_slice() };
+
+    let mut lines = vec![Message::new(None, "CAP", Some(vec!["LS", "302"]), None)];
+
+    if !config.password.is_empty()
+    {
+        lines.push(Message::new(None, "PASS", Some(vec![config.password.as_slice()]), None));
+    }
+
+    lines.push(Message::new(None, "NICK", Some(vec![nick]), None));
+    lines.push(Message::new(None, "USER", Some(vec![user, "0", "*"]), Some(real)));
+
+    lines
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn rx_task(generation: uint,
+           server: Arc<NetIrcServer>,
+           tx: Sender<Incoming>)
+{
+    spawn(move ||
+          {
+              for message in server.iter()
+              {
+                  debug!("{}", message.into_string());
+
+                  let (tags, message) = split_tags(message);
+                  let when = server_time(&tags).unwrap_or_else(|| time::get_time());
+                  tx.send(Incoming::Received(generation, when, tags, message));
+              }
+              tx.send(Incoming::Closed(generation));
+          });
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// The connection to one irc server, remade with exponential backoff whenever
+// it is lost
+pub struct Link
+{
+    config: Config,
+    // a client certificate to present
+    certificate: Option<TlsSettings>,
+    generation: uint,
+    server: Option<Arc<NetIrcServer>>,
+    // the socket under server, shut down to stop the task reading from it
+    socket: Option<TcpStream>,
+    attempts: uint,
+    retry_at: Timespec,
+    last_activity: Timespec,
+    ping_sent: bool
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Link
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(config: Config, credentials: &Option<Credentials>) -> Link
+    {
+        Link{config: config,
+             certificate: credentials.as_ref().and_then(|c| c.certificate.clone()),
+             generation: 0,
+             server: None,
+             socket: None,
+             attempts: 0,
+             retry_at: time::get_time(),
+             last_activity: time::get_time(),
+             ping_sent: false}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Connect and register, messages from the server will be sent to tx
+    pub fn connect(&mut self, tx: &Sender<Incoming>) -> bool
+    {
+        self.generation += 1;
+
+        let server = match self.open()
+            {
+                Ok(s)  => Arc::new(s),
+                Err(e) =>
+                {
+                    println!("Unable to connect to {}: {}", self.config.server, e);
+                    self.schedule_retry();
+                    return false;
+                }
+            };
+
+        rx_task(self.generation, server.clone(), tx.clone());
+
+        for line in registration(&self.config).into_iter()
+        {
+            if let Err(e) = server.send_message(line)
+            {
+                println!("Unable to register with {}: {}", self.config.server, e);
+                self.shutdown();
+                self.schedule_retry();
+                return false;
+            }
+        }
+
+        self.server = Some(server);
+        self.attempts = 0;
+        self.last_activity = time::get_time();
+        self.ping_sent = false;
+
+        true
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The connection is made here rather than by the irc crate so a client
+    // certificate can be presented, and so the socket can be shut down when
+    // the connection is abandoned
+    fn open(&mut self) -> IoResult<NetIrcServer>
+    {
+        let socket = try!(TcpStream::connect((self.config.server.as_slice(), self.config.port)));
+        self.socket = Some(socket.clone());
+
+        let (reader, writer) = if self.config.use_ssl
+            {
+                let stream = try!(self.tls(socket));
+                (NetStream::SslTcpStream(stream.clone()), NetStream::SslTcpStream(stream))
+            }
+            else
+            {
+                (NetStream::UnsecuredTcpStream(socket.clone()), NetStream::UnsecuredTcpStream(socket))
+            };
+
+        let connection = Connection::new(BufferedReader::new(reader), BufferedWriter::new(writer));
+
+        Ok(IrcServer::from_connection(self.config.clone(), connection))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn tls(&self, socket: TcpStream) -> IoResult<SslStream<TcpStream>>
+    {
+        let tls_error = |e: String| IoError{kind: OtherIoError, desc: "TLS error", detail: Some(e)};
+
+        let settings = match self.certificate
+            {
+                Some(ref t) => t.clone(),
+                None        => TlsSettings{certificate: None, key: None, ca: None}
+            };
+
+        let context = try!(settings.context(false).map_err(|e| tls_error(e.to_string())));
+        connect_tls(&context, socket, self.config.server.as_slice())
+            .map_err(|e| tls_error(e.to_string()))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn schedule_retry(&mut self)
+    {
+        self.attempts += 1;
+
+        let delay = cmp::min(1i64 << cmp::min(self.attempts, 16), MAX_BACKOFF);
+        self.retry_at = time::get_time() + Duration::seconds(delay);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn disconnected(&mut self, reason: &str)
+    {
+        if self.server.is_none()
+        {
+            return;
+        }
+
+        println!("Lost connection to {}: {}", self.config.server, reason);
+
+        self.shutdown();
+        self.server = None;
+        self.schedule_retry();
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The task reading from the old connection holds on to it until its read
+    // fails, which could be never if the server has gone quiet
+    fn shutdown(&mut self)
+    {
+        if let Some(mut s) = self.socket.take()
+        {
+            let _ = s.close_read();
+            let _ = s.close_write();
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn is_current(&self, generation: uint) -> bool
+    {
+        self.server.is_some() && generation == self.generation
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn received(&mut self)
+    {
+        self.last_activity = time::get_time();
+        self.ping_sent = false;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Called regularly to reconnect once the backoff has passed and to notice
+    // a server that has stopped talking.  Returns true when a new connection
+    // has been made.
+    pub fn tick(&mut self, tx: &Sender<Incoming>) -> bool
+    {
+        let now = time::get_time();
+
+        if self.server.is_none()
+        {
+            return now >= self.retry_at && self.connect(tx);
+        }
+
+        let idle = (now - self.last_activity).num_seconds();
+
+        if idle > PING_TIMEOUT
+        {
+            self.disconnected("Ping timeout");
+        }
+        else if idle > PING_INTERVAL && !self.ping_sent
+        {
+            self.send(PING(self.config.server.as_slice(), None));
+            self.ping_sent = true;
+        }
+
+        false
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Returns false when the command couldn't be sent
+    pub fn send(&self, command: Command) -> bool
+    {
+        match self.server
+        {
+            Some(ref s) => match s.send(command)
+                {
+                    Ok(_)  => true,
+                    Err(e) => { println!("Unable to send to {}: {}", self.config.server, e); false }
+                },
+            None => false
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // For what Command has no variant for, such as CAP and AUTHENTICATE
+    pub fn send_raw(&self, command: &str, args: Vec<&str>, suffix: Option<&str>) -> bool
+    {
+        match self.server
+        {
+            Some(ref s) => match s.send_message(Message::new(None, command, Some(args), suffix))
+                {
+                    Ok(_)  => true,
+                    Err(e) => { println!("Unable to send to {}: {}", self.config.server, e); false }
+                },
+            None => false
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn state(&self) -> ConnectionState
+    {
+        match self.server
+        {
+            Some(_) => ConnectionState::Connected,
+            None    => ConnectionState::Reconnecting(self.attempts, self.retry_at)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod test
+{
+    use super::{registration, server_time, split_tags, unescape, Tags};
+
+    use irc::data::{Config, Message};
+    use serialize::json;
+    use time::Timespec;
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn config(password: &str) -> Config
+    {
+        let text = format!(r#"{{"owners": [], "nickname": "nick", "username": "user",
+                               "realname": "Real Name", "password": "{}",
+                               "server": "irc.example.org", "port": 6697, "use_ssl": true,
+                               "encoding": "UTF-8", "channels": [], "options": {{}}}}"#,
+                           password);
+        json::decode(text.as_slice()).unwrap()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn sent(config: &Config) -> Vec<String>
+    {
+        registration(config).into_iter().map(|m| m.into_string()).collect()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn registers_after_asking_for_capabilities()
+    {
+        assert_eq!(sent(&config("hunter2")),
+                   vec!["CAP LS 302\r\n".to_string(),
+                        "PASS hunter2\r\n".to_string(),
+                        "NICK nick\r\n".to_string(),
+                        "USER user 0 * :Real Name\r\n".to_string()]);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn leaves_out_pass_without_a_password()
+    {
+        assert_eq!(sent(&config("")),
+                   vec!["CAP LS 302\r\n".to_string(),
+                        "NICK nick\r\n".to_string(),
+                        "USER user 0 * :Real Name\r\n".to_string()]);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn leaves_cap_end_to_negotiation()
+    {
+        assert!(!sent(&config("hunter2")).iter().any(|l| l.as_slice().starts_with("CAP END")));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn parse(line: &str) -> Message
+    {
+        from_str::<Message>(line).unwrap()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn tags(pairs: &[(&str, &str)]) -> Tags
+    {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn unescapes_tag_values()
+    {
+        assert_eq!(unescape("a\\sb\\:c"), "a b;c".to_string());
+        assert_eq!(unescape("\\r\\n"), "\r\n".to_string());
+        assert_eq!(unescape("back\\\\slash"), "back\\slash".to_string());
+        assert_eq!(unescape("\\x"), "x".to_string());
+        assert_eq!(unescape("trailing\\"), "trailing".to_string());
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn splits_tags_from_the_message()
+    {
+        let (found, message) =
+            split_tags(parse("@time=2014-12-01T18:30:00.000Z;msgid=a\\sb;+draft :nick!u@h PRIVMSG #rust :hi there\r\n"));
+
+        assert_eq!(found, tags(&[("time", "2014-12-01T18:30:00.000Z"),
+                                 ("msgid", "a b"),
+                                 ("+draft", "")]));
+        assert_eq!(message.prefix, Some("nick!u@h".to_string()));
+        assert_eq!(message.command, "PRIVMSG".to_string());
+        assert_eq!(message.args, vec!["#rust".to_string()]);
+        assert_eq!(message.suffix, Some("hi there".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn leaves_untagged_messages_alone()
+    {
+        let (found, message) = split_tags(parse(":nick!u@h PRIVMSG #rust :hi\r\n"));
+
+        assert!(found.is_empty());
+        assert_eq!(message.command, "PRIVMSG".to_string());
+        assert_eq!(message.suffix, Some("hi".to_string()));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn reads_server_time()
+    {
+        assert_eq!(server_time(&tags(&[("time", "2014-12-01T18:30:00.000Z")])),
+                   Some(Timespec::new(1417458600, 0)));
+        assert_eq!(server_time(&tags(&[("time", "2014-12-01T18:30:00Z")])),
+                   Some(Timespec::new(1417458600, 0)));
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn ignores_missing_or_bad_server_time()
+    {
+        assert_eq!(server_time(&tags(&[("msgid", "1")])), None);
+        assert_eq!(server_time(&tags(&[("time", "yesterday")])), None);
+    }
+}
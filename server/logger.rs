@@ -0,0 +1,188 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::fmt;
+use std::io;
+use std::io::{File, fs};
+use std::io::fs::PathExtensions;
+use std::os;
+
+use time;
+use time::Timespec;
+
+use irc::data::message::Message;
+
+use circ_comms;
+use history;
+use irc_channel;
+
+///////////////////////////////////////////////////////////////////////////////
+pub static DEFAULT_FORMAT: &'static str = "[{time}] {command} <{nick}> {message}";
+pub static DEFAULT_TIME_FORMAT: &'static str = "%H:%M:%S";
+
+///////////////////////////////////////////////////////////////////////////////
+#[deriving(Show, Clone)]
+pub struct LogSettings
+{
+    pub directory: Path,
+    pub format: String,
+    pub time_format: String
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl LogSettings
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn default_directory() -> Path
+    {
+        match os::homedir()
+        {
+            Some(home) => home.join(".circd").join("logs"),
+            None       => Path::new("/tmp/circd-logs")
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new() -> LogSettings
+    {
+        LogSettings{directory: LogSettings::default_directory(),
+                    format: DEFAULT_FORMAT.to_string(),
+                    time_format: DEFAULT_TIME_FORMAT.to_string()}
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Keep path separators in channel names from escaping the log directory
+fn sanitize(name: &str) -> String
+{
+    name.chars().map(|c| if c == '/' || c == '\0' { '_' } else { c }).collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Where the logs of a channel are kept
+pub fn directory(settings: &LogSettings, network: &str, channel: &str) -> Path
+{
+    settings.directory.join(sanitize(network)).join(sanitize(channel))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Append only log of a single channel.  A new file is started every day:
+// <directory>/<network>/<channel>/<date>.log
+// Messages also go to the searchable history file for the day.
+pub struct ChannelLog
+{
+    settings: LogSettings,
+    directory: Path,
+    date: String,
+    file: Option<File>,
+    history: Option<File>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl fmt::Show for ChannelLog
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "ChannelLog({})", self.directory.display())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl ChannelLog
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(settings: &LogSettings, network: &str, channel: &str) -> ChannelLog
+    {
+        ChannelLog{settings: settings.clone(),
+                   directory: directory(settings, network, channel),
+                   date: String::new(),
+                   file: None,
+                   history: None}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn open(&mut self, date: &str) -> io::IoResult<()>
+    {
+        if !self.directory.exists()
+        {
+            try!(fs::mkdir_recursive(&self.directory, io::USER_RWX));
+        }
+
+        let path = self.directory.join(format!("{}.log", date));
+        self.file = Some(try!(File::open_mode(&path, io::Append, io::Write)));
+
+        let path = self.directory.join(format!("{}.{}", date, history::EXTENSION));
+        self.history = Some(try!(File::open_mode(&path, io::Append, io::Write)));
+
+        self.date = date.to_string();
+
+        Ok(())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn format(&self, time: Timespec, msg: &Message) -> String
+    {
+        // the time format was checked when the configuration was read
+        let stamp = match time::at(time).strftime(self.settings.time_format.as_slice())
+            {
+                Ok(s)  => s.to_string(),
+                Err(_) => String::new()
+            };
+        let nick = match msg.prefix
+            {
+                Some(ref p) => p.as_slice().split('!').next().unwrap().to_string(),
+                None        => String::new()
+            };
+        let text = match msg.suffix
+            {
+                Some(ref s) => s.clone(),
+                None        => String::new()
+            };
+
+        circ_comms::substitute(self.settings.format.as_slice(),
+                               &[("{time}", stamp.as_slice()),
+                                 ("{command}", msg.command.as_slice()),
+                                 ("{nick}", nick.as_slice()),
+                                 ("{message}", text.as_slice())])
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn write(&mut self, time: Timespec, msg: &Message)
+    {
+        let date = format!("{}", time::at(time).strftime("%Y-%m-%d").unwrap());
+
+        if self.file.is_none() || self.date != date
+        {
+            if let Err(e) = self.open(date.as_slice())
+            {
+                println!("Unable to open log in {}: {}", self.directory.display(), e);
+                self.file = None;
+                self.history = None;
+                return;
+            }
+        }
+
+        let line = self.format(time, msg);
+
+        if let Some(ref mut f) = self.file
+        {
+            if let Err(e) = f.write_line(line.as_slice())
+            {
+                println!("Unable to write log in {}: {}", self.directory.display(), e);
+            }
+        }
+
+        if msg.command.as_slice() != "PRIVMSG" && msg.command.as_slice() != "NOTICE"
+        {
+            return;
+        }
+
+        let line = history::encode(&irc_channel::comms_message(time, msg));
+
+        if let Some(ref mut f) = self.history
+        {
+            if let Err(e) = f.write_line(line.as_slice())
+            {
+                println!("Unable to write history in {}: {}", self.directory.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+///////////////////////////////////////////////////////////////////////////////
+use circ_comms;
+use circ_comms::{Request, Response, Transport};
+
+use config;
+use connection::{Connection, Subscriber};
+
+///////////////////////////////////////////////////////////////////////////////
+// Cheap to clone, every client is served with its own copy
+#[deriving(Clone)]
+pub struct Networks
+{
+    connections: Vec<Connection>
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Networks
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(settings: config::Settings) -> Networks
+    {
+        let mut connections = Vec::new();
+
+        for n in settings.networks.into_iter()
+        {
+            connections.push(Connection::new(n, settings.log.clone(), settings.highlights.clone()));
+        }
+
+        Networks{connections: connections}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn has_channel(connection: &Connection, channel: &str) -> bool
+    {
+        match connection.request_response(Request::ListChannels)
+        {
+            Response::Channels(c) =>
+                c.iter().any(|&(_, ref names)| names.iter().any(|n| n.as_slice() == channel)),
+            _ => false
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn is_connected(connection: &Connection) -> bool
+    {
+        match connection.request_response(Request::GetStatus(String::new()))
+        {
+            Response::Status(s) => s.iter().any(|n| n.connection == circ_comms::ConnectionState::Connected),
+            _ => false
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn names(connections: &Vec<&Connection>) -> String
+    {
+        connections.iter().map(|c| c.network.clone()).collect::<Vec<String>>().connect(", ")
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Find the connection a request should be sent to.  Without an explicit
+    // network the channel must be known to exactly one network, or, for a
+    // channel no network knows yet, only one network may be connected.
+    fn find(&self, network: &Option<String>, channel: &str) -> Result<&Connection, String>
+    {
+        match *network
+        {
+            Some(ref name) =>
+                match self.connections.iter().find(|c| c.network == *name)
+                {
+                    Some(c) => Ok(c),
+                    None    => Err(format!("Unknown network {}", name))
+                },
+            None if self.connections.len() == 1 => Ok(&self.connections[0]),
+            None =>
+            {
+                let found: Vec<&Connection> =
+                    self.connections.iter()
+                                    .filter(|c| Networks::has_channel(*c, channel))
+                                    .collect();
+
+                if found.len() == 1
+                {
+                    return Ok(found[0]);
+                }
+                else if !found.is_empty()
+                {
+                    return Err(format!("Channel {} is on several networks, use -n with one of {}",
+                                       channel, Networks::names(&found)));
+                }
+
+                let connected: Vec<&Connection> =
+                    self.connections.iter().filter(|c| Networks::is_connected(*c)).collect();
+
+                match connected.len()
+                {
+                    1 => Ok(connected[0]),
+                    0 => Err(format!("Channel {} isn't known and no network is connected", channel)),
+                    _ => Err(format!("Channel {} isn't known on any network, use -n with one of {}",
+                                     channel, Networks::names(&connected)))
+                }
+            }
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    fn channel_of(request: &Request) -> Option<(Option<String>, String)>
+    {
+        match *request
+        {
+            Request::GetMessages(ref n, ref c, _) => Some((n.clone(), c.clone())),
+            Request::Peek(ref n, ref c, _)     => Some((n.clone(), c.clone())),
+            Request::GetUsers(ref n, ref c)    => Some((n.clone(), c.clone())),
+            Request::GetUsersStatus(ref n, ref c) => Some((n.clone(), c.clone())),
+            Request::Join(ref n, ref c)        => Some((n.clone(), c.clone())),
+            Request::Part(ref n, ref c)        => Some((n.clone(), c.clone())),
+            Request::SendMessage(ref n, ref c, _) => Some((n.clone(), c.clone())),
+            Request::SearchHistory{ref network, ref channel, ..} =>
+                Some((network.clone(), channel.clone())),
+            _ => None
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A request without a response, which can still fail to find a network
+    pub fn request(&self, request: Request) -> Result<(), String>
+    {
+        match Networks::channel_of(&request)
+        {
+            Some((network, channel)) =>
+            {
+                let connection = try!(self.find(&network, channel.as_slice()));
+                connection.request(request)
+            },
+            None =>
+            {
+                // the networks that are still running get it regardless
+                let mut result = Ok(());
+                for c in self.connections.iter()
+                {
+                    if let Err(e) = c.request(request.clone())
+                    {
+                        result = Err(e);
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Quit every network, returning once they have all sent QUIT
+    pub fn quit(&self)
+    {
+        for c in self.connections.iter()
+        {
+            c.quit();
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Pass the channels from a reloaded configuration on to each network.
+    // The connections are kept, so networks can't be added or removed.
+    pub fn reconfigure(&self, settings: config::Settings)
+    {
+        for c in self.connections.iter()
+        {
+            if !settings.networks.iter().any(|n| n.name == c.network)
+            {
+                println!("Network {} stays connected until circd is restarted", c.network);
+            }
+        }
+
+        for n in settings.networks.into_iter()
+        {
+            match self.connections.iter().find(|c| c.network == n.name)
+            {
+                Some(c) => if let Err(e) = c.configure(n.channels) { println!("{}", e) },
+                None    => println!("Network {} will be connected when circd is restarted", n.name)
+            }
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Hand a client over to every network it follows.  A task of its own
+    // writes the events so the client can't hold anything else up.
+    pub fn subscribe(&self, id: uint, network: Option<String>, channels: Vec<String>,
+                     mut stream: Transport)
+    {
+        let connections: Vec<&Connection> =
+            self.connections.iter()
+                            .filter(|c| match network { Some(ref n) => c.network == *n, None => true })
+                            .collect();
+
+        if connections.is_empty()
+        {
+            let error = Response::Error(format!("Unknown network {}", network.unwrap()));
+            if let Err(e) = circ_comms::write_response(&mut stream, id, error)
+            {
+                println!("Unable to send response: {}", e);
+            }
+            return;
+        }
+
+        let (tx, rx) = channel();
+
+        for c in connections.iter()
+        {
+            if let Err(e) = c.subscribe(Subscriber{channels: channels.clone(), tx: tx.clone()})
+            {
+                println!("{}", e);
+            }
+        }
+
+        spawn(move ||
+              {
+                  // stop, dropping rx, once the client has gone away
+                  for response in rx.iter()
+                  {
+                      if circ_comms::write_response(&mut stream, id, response).is_err()
+                      {
+                          break;
+                      }
+                  }
+              });
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn request_response(&self, request: Request) -> Response
+    {
+        match Networks::channel_of(&request)
+        {
+            Some((network, channel)) =>
+                match self.find(&network, channel.as_slice())
+                {
+                    Ok(c)  => c.request_response(request),
+                    Err(e) => circ_comms::Response::Error(e)
+                },
+            None =>
+            {
+                let mut channels = Vec::new();
+                let mut statuses = Vec::new();
+
+                for c in self.connections.iter()
+                {
+                    match c.request_response(request.clone())
+                    {
+                        Response::Channels(x) => channels.extend(x.into_iter()),
+                        Response::Status(x)   => statuses.extend(x.into_iter()),
+                        r => return r
+                    }
+                }
+
+                match request
+                {
+                    Request::ListChannels => Response::Channels(channels),
+                    Request::GetStatus(_) => Response::Status(statuses),
+                    r => Response::Error(format!("Unable to combine responses for {}", r))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+///////////////////////////////////////////////////////////////////////////////
+use std::cmp;
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use config;
+use isupport::same_nick;
+use link::Link;
+
+use irc::data::Message;
+use irc::data::Command::{NICK, PRIVMSG};
+
+///////////////////////////////////////////////////////////////////////////////
+// Seconds between attempts at getting the nick we want back, and how long
+// NickServ is given to disconnect whoever has it
+static REGAIN_INTERVAL: i64 = 60;
+static GHOST_WAIT: i64 = 5;
+
+///////////////////////////////////////////////////////////////////////////////
+// Our nick on a network.  While registering a nick that is taken is replaced
+// by the next alternate, or the same nick with an underscore added (or a
+// digit at the end when that would make it too long).  Once registered the
+// configured nick is asked for every so often, ghosting whoever has it if
+// NickServ may be asked to.  A nick asked for with Request::ChangeNick is
+// only asked for once: when it is taken the client is told and we stay as we
+// are.
+pub struct Nicks
+{
+    // what the irc crate registers with
+    configured: String,
+    alternates: Vec<String>,
+    wanted: String,
+    current: String,
+    tried: uint,
+    digits: uint,
+    registered: bool,
+    // whether wanted has been asked for since registering, for a nick that
+    // isn't the configured one
+    asked: bool,
+    // the NickServ password, when ghosting is allowed
+    ghost: Option<String>,
+    ghost_sent: bool,
+    regain_at: Timespec
+}
+
+///////////////////////////////////////////////////////////////////////////////
+impl Nicks
+{
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn new(network: &config::Network) -> Nicks
+    {
+        let ghost = match network.credentials
+            {
+                Some(config::Credentials{ghost: true, ref password, ..}) => password.clone(),
+                _ => None
+            };
+
+        let nick = network.config.nickname.clone();
+
+        Nicks{configured: nick.clone(), alternates: network.alt_nicks.clone(), wanted: nick.clone(),
+              current: nick, tried: 0, digits: 0, registered: false, asked: false, ghost: ghost,
+              ghost_sent: false, regain_at: time::get_time()}
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A new connection registers with the configured nick again
+    pub fn reset(&mut self)
+    {
+        self.current = self.configured.clone();
+        self.tried = 0;
+        self.digits = 0;
+        self.registered = false;
+        self.asked = false;
+        self.ghost_sent = false;
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    pub fn current(&self) -> &str
+    {
+        self.current.as_slice()
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Request::ChangeNick, which is also the nick kept from now on
+    pub fn change(&mut self, link: &Link, nick: String)
+    {
+        self.wanted = nick;
+        self.ghost_sent = false;
+        self.asked = false;
+
+        if self.registered && self.current != self.wanted
+        {
+            link.send(NICK(self.wanted.as_slice()));
+            self.asked = true;
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Only the configured nick is ghosted and asked for again and again
+    fn primary(&self) -> bool
+    {
+        same_nick(self.wanted.as_slice(), self.configured.as_slice())
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // RPL_WELCOME: <nick> :Welcome..., the nick registration ended up with
+    pub fn welcome(&mut self, msg: &Message)
+    {
+        if let Some(nick) = msg.args.as_slice().first()
+        {
+            self.current = nick.clone();
+        }
+
+        self.registered = true;
+        self.regain_at = time::get_time();
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // A NICK from the server, returns true when it was ours that changed
+    pub fn renamed(&mut self, old: &str, new: &str) -> bool
+    {
+        if !same_nick(old, self.current.as_slice())
+        {
+            return false;
+        }
+
+        self.current = new.to_string();
+        if self.current == self.wanted
+        {
+            self.ghost_sent = false;
+        }
+
+        true
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // The nick to try after the current one was refused, None once there is
+    // nothing left to try
+    fn next(&mut self, nicklen: uint) -> Option<String>
+    {
+        if self.tried < self.alternates.len()
+        {
+            return Some(self.alternates[self.tried].clone());
+        }
+
+        let underscored = format!("{}_", self.current);
+        if underscored.as_slice().char_len() <= nicklen
+        {
+            return Some(underscored);
+        }
+
+        if self.digits >= 10
+        {
+            return None;
+        }
+
+        let base: String = self.wanted.as_slice().chars().take(nicklen.saturating_sub(1)).collect();
+        self.digits += 1;
+        Some(format!("{}{}", base, self.digits - 1))
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // ERR_ERRONEUSNICKNAME, ERR_NICKNAMEINUSE or ERR_UNAVAILRESOURCE:
+    // <me> <nick> :reason.  nicklen is what the server has said it accepts.
+    // Returns what the client should be told, if anything.
+    pub fn in_use(&mut self, link: &Link, msg: &Message, nicklen: uint) -> Option<String>
+    {
+        let nick = match msg.args.as_slice().get(1) { Some(n) => n.clone(), None => return None };
+        let erroneous = msg.command.as_slice() == "432";
+
+        if !self.registered
+        {
+            // a nick that was only taken was long enough, the server may not
+            // have said how long a nick can be before registering
+            let nicklen = if erroneous { nicklen } else { cmp::max(nicklen, nick.as_slice().char_len()) };
+
+            // registration doesn't finish until some nick is accepted
+            match self.next(nicklen)
+            {
+                Some(next) =>
+                {
+                    println!("Nick {} can't be used, trying {}", nick, next);
+                    self.tried += 1;
+                    self.current = next;
+                    link.send(NICK(self.current.as_slice()));
+                    None
+                },
+                None =>
+                {
+                    println!("Nick {} can't be used and there are no others to try", nick);
+                    Some(format!("Nick {} can't be used and there are no others to try", nick))
+                }
+            }
+        }
+        else if erroneous
+        {
+            // asking for it again won't help
+            let reason = msg.suffix.as_ref().map_or("", |s| s.as_slice());
+            println!("Nick {} isn't allowed: {}", nick, reason);
+            self.wanted = self.current.clone();
+            Some(format!("Nick {} isn't allowed: {}", nick, reason))
+        }
+        else if same_nick(nick.as_slice(), self.wanted.as_slice()) && !self.primary()
+        {
+            // someone else's nick isn't ghosted, nor asked for again
+            println!("Nick {} is taken", nick);
+            self.wanted = self.current.clone();
+            Some(format!("Nick {} is taken, staying as {}", nick, self.current))
+        }
+        else if same_nick(nick.as_slice(), self.wanted.as_slice())
+        {
+            let now = time::get_time();
+
+            match self.ghost
+            {
+                Some(ref password) if !self.ghost_sent =>
+                {
+                    let ghost = format!("GHOST {} {}", self.wanted, password);
+                    link.send(PRIVMSG("NickServ", ghost.as_slice()));
+                    self.ghost_sent = true;
+                    self.regain_at = now + Duration::seconds(GHOST_WAIT);
+                },
+                _ => self.regain_at = now + Duration::seconds(REGAIN_INTERVAL)
+            }
+
+            None
+        }
+        else
+        {
+            println!("Nick {} is taken", nick);
+            None
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Called regularly to ask for the nick we want when we don't have it,
+    // only once unless it is the configured nick
+    pub fn tick(&mut self, link: &Link)
+    {
+        let now = time::get_time();
+
+        if !self.registered || self.current == self.wanted || now < self.regain_at ||
+           (self.asked && !self.primary())
+        {
+            return;
+        }
+
+        link.send(NICK(self.wanted.as_slice()));
+        self.asked = true;
+        self.regain_at = now + Duration::seconds(REGAIN_INTERVAL);
+    }
+}
@@ -0,0 +1,65 @@
+///////////////////////////////////////////////////////////////////////////////
+// TLS between circ and circd, with the certificates in tests/tls: ca.pem
+// signed server.pem for localhost, other-ca.pem signed nothing here.
+extern crate circ_comms;
+
+///////////////////////////////////////////////////////////////////////////////
+use circ_comms::{TlsSettings, Transport};
+use std::io::{Acceptor, Listener};
+use std::io::net::tcp::TcpListener;
+
+///////////////////////////////////////////////////////////////////////////////
+// Serves one TLS connection, writing a byte to it, and returns the port
+fn serve_once() -> u16
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.socket_name().unwrap().port;
+    let mut acceptor = listener.listen().unwrap();
+
+    spawn(move ||
+    {
+        let settings = TlsSettings{certificate: Some(Path::new("tests/tls/server.pem")),
+                                   key: Some(Path::new("tests/tls/server.key")),
+                                   ca: None};
+        let context = settings.context(true).unwrap();
+
+        let stream = acceptor.accept().unwrap();
+        if let Ok(mut t) = Transport::accept_tls(stream, &context)
+        {
+            let _ = t.write_u8(42);
+        }
+    });
+
+    port
+}
+
+///////////////////////////////////////////////////////////////////////////////
+fn connect(host: &str, ca: &str) -> Option<Transport>
+{
+    let address = format!("{}:{}", host, serve_once());
+    let settings = TlsSettings{certificate: None, key: None, ca: Some(Path::new(ca))};
+
+    Transport::connect_tcp(address.as_slice(), Some(&settings)).ok()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[test]
+fn accepts_certificate_signed_by_ca()
+{
+    let mut t = connect("localhost", "tests/tls/ca.pem").expect("handshake failed");
+    assert_eq!(t.read_u8().unwrap(), 42);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[test]
+fn rejects_certificate_signed_by_another_ca()
+{
+    assert!(connect("localhost", "tests/tls/other-ca.pem").is_none());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+#[test]
+fn rejects_certificate_for_another_host()
+{
+    assert!(connect("127.0.0.1", "tests/tls/ca.pem").is_none());
+}